@@ -1,17 +1,60 @@
 /*! Derivation of [`Serialize`][serialize] and [`Deserialize`][deserialize] that replaces struct keys with numerical indices.
 
+Unit variants of enums are serialized as the integer index of the variant.  Newtype variants are serialized as a map with a single entry from the index of the variant to the value of its field.  Deserializing an enum with newtype variants requires a self-describing format.  Enums with the `tag` attribute are serialized as a map instead, see below.
+
+Tuple structs are serialized like structs with named fields, using the position of the field as its index (plus `offset`).  Newtype structs, i. e. tuple structs with a single field, are serialized transparently as their inner value and only support the `with`, `serialize_with` and `deserialize_with` field attributes.
+
 ### Usage example
 
 #### Struct attributes
 
 - `auto_index`: Automatically assign indices to the fields based on the order in the source code.  It is recommended to instead use the `index` attribute for all fields to explicitly assign indices.
-- `offset = ?`: If `auto_index` is set, use the given index for the first field instead of starting with zero.
+- `offset = ?`: If `auto_index` is set, use the given index for the first field instead of starting with zero.  The offset may be negative.
+- `array`: Serialize the struct as an array instead of a map, with the fields sorted by their index.  The indices must be contiguous and `skip_serializing_if` cannot be used.
+- `default`: If a field is missing during deserialization, take its value from `Default::default()` of the struct.  The `default` attribute of a field takes precedence.
+- `default = "path"`: Like `default`, but call the given function to get the default value of the struct.
+- `deny_unknown_fields`: Return an error for unknown keys during deserialization instead of ignoring them.  In `array` mode, return an error for trailing elements.
+- `canonical`: Return an error during deserialization if the keys are not in the order required by the [CTAP2 canonical CBOR encoding form][ctap-cbor], i. e. unsigned integers before negative integers, each sorted by their absolute value, which matches a bytewise comparison of the encoded keys.  Cannot be used together with `array`.
+- `human_readable`: Use the names of the fields as keys if the serializer is human-readable, e. g. for JSON.  During deserialization, both the index and the name of a field are accepted as keys, which requires a self-describing format.  Cannot be used together with `array`.
+
+- `bound = "T: Trait"`: Use the given where predicates instead of the inferred `Serialize` and `Deserialize` bounds for the type parameters.
+- `bound(serialize = "T: Trait", deserialize = "T: Trait")`: Like `bound`, but set the predicates for the `Serialize` and `Deserialize` implementations separately.  Either of them can be omitted to keep the inferred bounds.
+- `validate = "path"`: After deserialization, call the given function with a reference to the value and return an error if it does not return `Ok(())`.  The function must return `Result<(), E>` where `E` implements `Display`.  The path may refer to `Self`.  This attribute can also be used for newtype structs.
+- `from = "Type"`: Deserialize the given type and convert it into this type using `From`.
+- `try_from = "Type"`: Deserialize the given type and convert it into this type using `TryFrom`.  The conversion error must implement `Display`.
+- `into = "Type"`: Serialize this type by converting a clone of it into the given type using `Into`.  This type must implement `Clone`.
+
+  If a type has both the `into` and the `from` or `try_from` attribute, its fields are ignored and don’t need indices.  This allows (de)serializing a type that upholds some invariants through an indexed struct that derives the implementations.
+- `crate = "path"`: Use the serde crate at the given path in the generated code, e. g. if serde is re-exported by another crate or renamed in `Cargo.toml`.  This attribute can also be used for enums and newtype structs.
+- `tag = ?`: Only for enums.  Serialize the enum as a map with an entry for the given index that holds the `tag_value` of the variant.  Variants can be unit variants or newtype variants whose field is a struct that derives `SerializeIndexed` and `DeserializeIndexed` without `array`.  The entries of this struct are merged into the map like for a flattened field, with all keys serialized as `i64` and the tag entry placed in canonical order.  The `deny_unknown_fields` attribute of the struct is honored.  During deserialization, the tag must be the first entry of the map, so maps from encoders that place other entries before the tag are rejected.  To make sure that the serialized maps can be deserialized, all indices of the structs must sort after the tag in canonical order, which is checked at compile time, unless `tag_first` is set.
+- `tag_first`: Only together with `tag`.  Always serialize the tag entry first, even if the struct of a variant has indices that sort before the tag.  This allows such indices, but the serialized map is not in canonical order then.
+
+Fields are always serialized in the canonical order described for `canonical`, independent of the order of their declaration.
+
+Like serde_derive, the derive macros only add `Serialize` and `Deserialize` bounds for type parameters that are used by fields that are not skipped and do not use a `with` function.  Type parameters only used in `PhantomData` are not bound.  Type parameters used by fields that take their value from `Default::default()`, e. g. skipped fields, are bound by `Default` for deserialization.  If a field uses an associated type of a type parameter, e. g. `T::Assoc`, the bound is added for the associated type instead of the type parameter.
 
 ### Field attributes
 
-- `index = ?`: Set the index for this field to the given field.  This attribute is required unless `auto_index` is set.  It cannot be used together with `auto_index`.
+- `index = ?`: Set the index for this field to the given field.  This attribute is required unless `auto_index` is set or the struct is a tuple struct.  It cannot be used together with `auto_index`.  Negative indices are supported; keys are serialized as `i64` instead of `usize` if any index is negative.
+- `index = "text"`: Use the given text string as the key for this field instead of an integer index.  Text and integer indices can be mixed in the same struct; fields with text indices are serialized after the fields with integer indices, sorted by the length of the text and then bytewise.  Deserializing a struct with text indices requires a self-describing format.  Text indices cannot be used together with `array`, `canonical`, `human_readable`, `unknown` or `flatten`, and a struct with text indices cannot be flattened.
+- `alias = ?`: Also accept the given index for this field during deserialization.  The field is still serialized with its `index`.  This attribute can be repeated and cannot be used in `array` mode.  Aliases must not collide with the index or the aliases of any field.
 - `skip`: Never serialize or deserialize this field.  This field still increases the assigned index if `auto_index` is used.
 - `skip(no_increment)`: Never serialize or deserialize this field and don’t increment the assigned index for this field if used together with the `auto_index` attribute.
+- `skip_serializing`: Never serialize this field.  It is still deserialized from its index, so it requires an index like any other field and always increases the assigned index if `auto_index` is used.
+- `skip_deserializing`: Never deserialize this field and use its default value instead, see `default`.  Like `skip_serializing`, it requires an index.  This index is treated like an unknown key during deserialization.
+- `default`: If the field is missing during deserialization, use `Default::default()` instead of returning an error.
+- `default = "path"`: If the field is missing during deserialization, call the given function to get the value instead of returning an error.
+- `validate = "path"`: Call the given function with a reference to the value of this field directly after deserializing it.  The function must return `Result<(), E>` where `E` implements `Display`.  If it returns an error, deserialization fails with an error that contains the name and the index of the field.  Default values are not validated.
+- `bound = "T: Trait"` or `bound(serialize = "T: Trait", deserialize = "T: Trait")`: Add the given where predicates instead of inferring bounds for the type parameters used by the type of this field.  Ignored if the struct has a `bound` attribute.
+- `unknown`: Collect all entries with unknown keys in this field during deserialization and serialize them together with the other fields in canonical order.  The field must have a map type with `i64` keys, an `insert` method returning `Option<_>` or `Result<Option<_>, _>` and an iterator over `(&key, &value)` pairs for references, like `BTreeMap<i64, V>` or `heapless::LinearMap<i64, V, N>`.  At most one field can have this attribute.  It does not have an index and cannot be combined with other field attributes, `array` or `deny_unknown_fields`.
+- `flatten`: Merge the entries of this field into the map of the struct.  The type of the field must be a struct that derives `SerializeIndexed` and `DeserializeIndexed` without `array`, and its indices must not collide with the indices of the outer struct.  Keys are serialized as `i64` if a field is flattened.  The entries of the flattened struct are sorted into the canonical order of the outer struct.  At most one field can be flattened.  With `deny_unknown_fields`, the error for unknown keys does not list the indices of the flattened struct.  The `unknown` field of a flattened struct is ignored.  The flattened field does not have an index and cannot be combined with other field attributes, `array` or `human_readable`.  A struct that contains a flattened field can be flattened into another struct for serialization, but not for deserialization, so deriving `DeserializeIndexed` for the outer struct fails to compile in that case.
+
+### Variant attributes
+
+- `index = ?`: Set the index for this variant.  If it is not set, the discriminant of the variant is used, which must then be an integer literal or implicit.
+- `tag_value = ?`: Set the value of the tag entry for this variant of an enum with the `tag` attribute.  This is an alias for `index`.
+- `other`: Deserialize all unknown indices into this variant, which must be a newtype variant with an `i64` field, e. g. `Unknown(i64)`.  During serialization, the value of the field is used as the index.  If an enum has this variant, all indices are serialized as `i64`.  This variant does not have an index.  At most one variant can have this attribute and it cannot be used for enums with the `tag` attribute.
+- `label = "text"`: Also accept the given text string for this unit variant during deserialization, like the `int / tstr` labels used by COSE.  The variant is still serialized as its index.  Accepting labels requires a self-describing format.  Labels must be unique and cannot be used for enums with the `tag` attribute.
 
 `serde-indexed` also supports these `serde` attributes:
 - [`deserialize_with`][deserialize-with]
@@ -42,6 +85,26 @@ pub struct SomeKeys {
 }
 ```
 
+Negative indices, e.g. for [COSE keys](https://www.rfc-editor.org/rfc/rfc9052#section-7):
+
+```
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub struct P256PublicKey {
+    #[serde(index = 1)]
+    pub kty: i8,
+    #[serde(index = 3)]
+    pub alg: i8,
+    #[serde(index = -1)]
+    pub crv: i8,
+    #[serde(index = -2)]
+    pub x: [u8; 32],
+    #[serde(index = -3)]
+    pub y: [u8; 32],
+}
+```
+
 Automatic index assignment:
 
 ```
@@ -114,6 +177,118 @@ pub struct SomeKeys {
 }
 ```
 
+Positional encoding as an array with `array`, e. g. for [`COSE_Sign1`](https://www.rfc-editor.org/rfc/rfc9052#section-4.2):
+
+```
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+#[serde_indexed(array, auto_index)]
+pub struct Sign1<'a> {
+    // position 0
+    #[serde(with = "serde_bytes")]
+    pub protected: &'a [u8],
+    // position 1
+    pub unprotected: Option<u8>,
+    // position 2
+    #[serde(with = "serde_bytes")]
+    pub payload: &'a [u8],
+    // position 3
+    #[serde(with = "serde_bytes")]
+    pub signature: &'a [u8],
+}
+```
+
+Use a default value for missing fields with `default`:
+
+```
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+fn default_pin_protocol() -> u8 {
+    1
+}
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub struct SomeKeys {
+    #[serde(index = 1)]
+    pub number: i32,
+    #[serde(index = 2, default)]
+    pub flag: bool,
+    #[serde(index = 3, default = "default_pin_protocol")]
+    pub pin_protocol: u8,
+}
+```
+
+Use the default value of the struct for missing fields:
+
+```
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+#[serde_indexed(default)]
+pub struct Config {
+    #[serde(index = 1)]
+    pub retries: u8,
+    #[serde(index = 2)]
+    pub timeout: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { retries: 3, timeout: 1000 }
+    }
+}
+```
+
+Collect unknown entries with `unknown`:
+
+```
+use std::collections::BTreeMap;
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub struct Extensions {
+    #[serde(index = 1)]
+    pub cred_protect: u8,
+    #[serde_indexed(unknown)]
+    pub unknown: BTreeMap<i64, u8>,
+}
+```
+
+Mix integer and text indices, e. g. for the options of a CTAP2 request:
+
+```
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub struct Options {
+    #[serde(index = "rk", default)]
+    pub rk: bool,
+    #[serde(index = "up", skip_serializing_if = "Option::is_none")]
+    pub up: Option<bool>,
+}
+```
+
+Share common entries between structs with `flatten`:
+
+```
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub struct Common {
+    #[serde(index = 1)]
+    pub version: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub struct Response {
+    #[serde(flatten)]
+    pub common: Common,
+    #[serde(index = 2)]
+    pub status: u8,
+}
+```
+
 Change the serialization or deserialization format with `deserialize_with`, `serialize_with` or `with`:
 
 ```
@@ -130,6 +305,95 @@ pub struct SomeKeys<'a> {
 }
 ```
 
+Unit-only enums, using the discriminant or the `index` attribute:
+
+```
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+#[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub enum Algorithm {
+    // index -7, also accepts "ES256" during deserialization
+    #[serde(label = "ES256")]
+    Es256 = -7,
+    // index -8
+    EdDsa = -8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub enum Extension {
+    #[serde(index = 1)]
+    CredProtect,
+    // keeps unknown indices, e. g. Unknown(3)
+    #[serde(other)]
+    Unknown(i64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub enum CredentialProtection {
+    #[serde(index = 1)]
+    Optional,
+    #[serde(index = 2)]
+    OptionalWithCredentialIdList,
+    #[serde(index = 3)]
+    Required,
+}
+```
+
+Enums with newtype variants, serialized as a map with a single entry:
+
+```
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub struct SetPin {
+    #[serde(index = 1)]
+    pub protocol: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub enum Command {
+    // serialized as 1
+    #[serde(index = 1)]
+    Reset,
+    // serialized as {2: {1: protocol}}
+    #[serde(index = 2)]
+    SetPin(SetPin),
+}
+```
+
+Internally tagged enums, using the `tag` attribute:
+
+```
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub struct OkpKey {
+    #[serde(index = -1)]
+    pub crv: i32,
+    #[serde(index = -2)]
+    pub x: [u8; 32],
+}
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+pub struct Ec2Key {
+    #[serde(index = -1)]
+    pub crv: i32,
+    #[serde(index = -2)]
+    pub x: [u8; 32],
+    #[serde(index = -3)]
+    pub y: [u8; 32],
+}
+
+#[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+#[serde_indexed(tag = 1)]
+pub enum CoseKey {
+    #[serde(tag_value = 1)]
+    Okp(OkpKey),
+    #[serde(tag_value = 2)]
+    Ec2(Ec2Key),
+}
+```
+
 [serialize]: https://docs.serde.rs/serde/ser/trait.Serialize.html
 [deserialize]: https://docs.serde.rs/serde/de/trait.Deserialize.html
 [deserialize-with]: https://serde.rs/field-attrs.html#deserialize_with
@@ -137,88 +401,580 @@ pub struct SomeKeys<'a> {
 [with]: https://serde.rs/field-attrs.html#with
 [skip-serializing-if]: https://serde.rs/field-attrs.html#skip_serializing_if
 [serde-cbor]: https://docs.rs/serde_cbor
+[ctap-cbor]: https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#ctap2-canonical-cbor-encoding-form
 */
 
 extern crate proc_macro;
 
 mod parse;
 
+use core::cmp::Ordering;
 use parse::Skip;
 use proc_macro::TokenStream;
-use proc_macro2::{Ident, Span};
-use quote::{format_ident, quote, quote_spanned};
+use proc_macro2::{Ident, Literal, Span};
+use quote::{format_ident, quote, quote_spanned, ToTokens};
 use syn::{
-    parse_macro_input, ImplGenerics, Lifetime, LifetimeParam, TypeGenerics, TypeParamBound,
-    WhereClause,
+    parse_macro_input, parse_quote, Generics, ImplGenerics, Lifetime, LifetimeParam, TypeGenerics,
+    WhereClause, WherePredicate,
 };
 
-use crate::parse::Input;
+use crate::parse::{Bounds, Data, DefaultValue, Input, StructAttrs};
+
+/// Computes the keys of the fields of an indexed struct.
+///
+/// Keys are `usize` unless any of the indices is negative, in which case they are `i64`.  If
+/// `human_readable` is set, the labels of the fields are used as keys for human-readable formats.
+/// If any field has a text index, keys are deserialized into the generated `__SerdeIndexedKey`
+/// enum, see `text_key`.
+#[derive(Clone, Copy)]
+struct Keys {
+    offset: i64,
+    signed: bool,
+    human_readable: bool,
+    text: bool,
+}
+
+impl Keys {
+    fn new(fields: &[parse::Field], attrs: &StructAttrs) -> Self {
+        let offset = attrs.offset;
+        // The keys of the unknown entries collected by the catch-all field and of the entries of
+        // flattened fields are always `i64`
+        let signed = fields.iter().any(|field| field.unknown || field.flatten)
+            || fields
+                .iter()
+                .flat_map(|field| field.index.iter().chain(&field.aliases))
+                .any(|index| index + offset < 0);
+        Self {
+            offset,
+            signed,
+            human_readable: attrs.human_readable,
+            text: fields.iter().any(|field| field.text_index.is_some()),
+        }
+    }
+
+    fn ty(self) -> proc_macro2::TokenStream {
+        if self.signed {
+            quote!(i64)
+        } else {
+            quote!(usize)
+        }
+    }
+
+    /// Generates code that rejects keys that are not in CTAP2 canonical order, see `canonical_rank`.
+    ///
+    /// For unsigned keys, the canonical order is the same as the numerical order.  Returns the
+    /// declaration of the state and the check for every key.
+    fn check_canonical_order(self) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+        let key_ty = self.ty();
+        let rank = if self.signed {
+            rank_function()
+        } else {
+            quote! {
+                fn __serde_indexed_rank(key: usize) -> usize {
+                    key
+                }
+            }
+        };
+        let state = quote! {
+            #rank
+            let mut __serde_indexed_previous_key: Option<#key_ty> = None;
+        };
+        let check = quote! {
+            if let Some(__serde_indexed_previous_key) = __serde_indexed_previous_key {
+                if __serde_indexed_rank(__serde_indexed_internal_key)
+                    <= __serde_indexed_rank(__serde_indexed_previous_key)
+                {
+                    return Err(serde::de::Error::custom(format_args!(
+                        "index `{}` is not in canonical order after index `{}`",
+                        __serde_indexed_internal_key,
+                        __serde_indexed_previous_key,
+                    )));
+                }
+            }
+            __serde_indexed_previous_key = Some(__serde_indexed_internal_key);
+        };
+        (state, check)
+    }
+
+    fn index(self, field: &parse::Field) -> i64 {
+        // index should only be none if the field is always skipped, so this should never panic
+        field
+            .index
+            .expect("index must be set for fields that are not skipped")
+            + self.offset
+    }
+
+    fn serialize_entry(
+        self,
+        field: &parse::Field,
+        value: proc_macro2::TokenStream,
+    ) -> proc_macro2::TokenStream {
+        let index = self.literal(field);
+        if self.human_readable {
+            let label = &field.label;
+            quote! {{
+                let value = #value;
+                if __serde_indexed_human_readable {
+                    map.serialize_entry(#label, value)?;
+                } else {
+                    map.serialize_entry(&#index, value)?;
+                }
+            }}
+        } else {
+            quote! {
+                map.serialize_entry(&#index, #value)?;
+            }
+        }
+    }
+
+    fn literal(self, field: &parse::Field) -> Literal {
+        match &field.text_index {
+            Some(text) => Literal::string(text),
+            None => self.key_literal(self.index(field)),
+        }
+    }
+
+    /// The index of the field as it is shown in error messages.
+    fn display(self, field: &parse::Field) -> String {
+        match &field.text_index {
+            Some(text) => format!("{text:?}"),
+            None => self.index(field).to_string(),
+        }
+    }
+
+    /// Compares the keys of two fields in canonical order: integer keys sorted by
+    /// `canonical_rank`, followed by text keys sorted by their length and then bytewise.
+    fn canonical_order(self, a: &parse::Field, b: &parse::Field) -> Ordering {
+        match (&a.text_index, &b.text_index) {
+            (None, None) => canonical_rank(self.index(a)).cmp(&canonical_rank(self.index(b))),
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(a), Some(b)) => (a.len(), a).cmp(&(b.len(), b)),
+        }
+    }
+
+    /// The keys that are accepted for a field during deserialization, i. e. its index and aliases.
+    fn pattern(self, field: &parse::Field) -> proc_macro2::TokenStream {
+        let aliases = field
+            .aliases
+            .iter()
+            .map(|alias| self.key_literal(alias + self.offset));
+        if !self.text {
+            let index = self.literal(field);
+            return quote!(#index #(| #aliases)*);
+        }
+        let aliases = aliases.map(|alias| quote!(__SerdeIndexedKey::Index(#alias)));
+        match &field.text_index {
+            Some(text) => quote!(__SerdeIndexedKey::Text(#text) #(| #aliases)*),
+            None => {
+                let index = self.literal(field);
+                quote!(__SerdeIndexedKey::Index(#index) #(| #aliases)*)
+            }
+        }
+    }
+
+    fn key_literal(self, index: i64) -> Literal {
+        if self.signed {
+            Literal::i64_suffixed(index)
+        } else {
+            Literal::usize_suffixed(index as usize)
+        }
+    }
+}
+
+fn serialize_member(
+    field: &parse::Field,
+    impl_generics_serialize: &ImplGenerics<'_>,
+    ty_generics_serialize: &TypeGenerics<'_>,
+    ty_generics: &TypeGenerics<'_>,
+    where_clause: Option<&WhereClause>,
+    ident: &Ident,
+) -> proc_macro2::TokenStream {
+    let member = &field.member;
+    match &field.serialize_with {
+        None => quote!(&self.#member),
+        Some(f) => {
+            let ty = &field.ty;
+            quote!({
+                    struct __InternalSerdeIndexedSerializeWith #impl_generics_serialize {
+                        value: &'__serde_indexed_lifetime #ty,
+                        phantom: ::core::marker::PhantomData<#ident #ty_generics>,
+                    }
+
+                    impl #impl_generics_serialize serde::Serialize for __InternalSerdeIndexedSerializeWith #ty_generics_serialize #where_clause {
+                        fn serialize<__S>(
+                            &self,
+                            __s: __S,
+                        ) -> ::core::result::Result<__S::Ok, __S::Error>
+                        where
+                            __S: serde::Serializer,
+                        {
+                            #f(self.value, __s)
+                        }
+                    }
+
+                    &__InternalSerdeIndexedSerializeWith { value: &self.#member, phantom: ::core::marker::PhantomData::<#ident #ty_generics> }
+            })
+        }
+    }
+}
+
+/// The position of an integer key in the CTAP2 canonical CBOR encoding form.
+///
+/// Keys are sorted by their major type, i. e. unsigned before negative integers, and then by
+/// their argument, which is the same as a bytewise comparison of their encodings.  This must match
+/// the `__serde_indexed_rank` function generated by `rank_function`.
+fn canonical_rank(index: i64) -> (bool, u64) {
+    if index < 0 {
+        (true, (-1 - index) as u64)
+    } else {
+        (false, index as u64)
+    }
+}
+
+/// Defines `__serde_indexed_rank`, which computes `canonical_rank` for `i64` keys at runtime.
+fn rank_function() -> proc_macro2::TokenStream {
+    quote! {
+        fn __serde_indexed_rank(key: i64) -> (bool, u64) {
+            if key < 0 {
+                (true, (-1 - key) as u64)
+            } else {
+                (false, key as u64)
+            }
+        }
+    }
+}
+
+fn rank_literal((negative, argument): (bool, u64)) -> proc_macro2::TokenStream {
+    let argument = Literal::u64_suffixed(argument);
+    quote!((#negative, #argument))
+}
+
+fn option_rank_literal(rank: Option<(bool, u64)>) -> proc_macro2::TokenStream {
+    match rank.map(rank_literal) {
+        Some(rank) => quote!(Some(#rank)),
+        None => quote!(None),
+    }
+}
+
+/// Returns a closure that accepts the `i64` keys whose rank is between the given ranks and that
+/// are accepted by the `within` closure, if it is set.
+///
+/// This requires the `__serde_indexed_rank` function defined by `rank_function`.
+fn rank_filter(
+    after: Option<(bool, u64)>,
+    before: Option<(bool, u64)>,
+    within: Option<&proc_macro2::TokenStream>,
+) -> proc_macro2::TokenStream {
+    let conditions: Vec<_> = within
+        .map(|within| quote!(#within(key)))
+        .into_iter()
+        .chain(after.map(|rank| {
+            let rank = rank_literal(rank);
+            quote!(__serde_indexed_rank(key) > #rank)
+        }))
+        .chain(before.map(|rank| {
+            let rank = rank_literal(rank);
+            quote!(__serde_indexed_rank(key) < #rank)
+        }))
+        .collect();
+    if conditions.is_empty() {
+        quote!(|_: i64| true)
+    } else {
+        quote!(|key: i64| #(#conditions)&&*)
+    }
+}
+
+/// Serializes the entries of the flattened fields and of the catch-all field for unknown entries
+/// whose keys are between the given ranks, in canonical order.
+///
+/// The keys of the unknown entries are only known at runtime, so the unknown entry with the lowest
+/// rank in the remaining range is selected repeatedly, after serializing the flattened entries
+/// that come before it.  Together with the sorted entries of the other fields, this keeps all
+/// entries in canonical order, as there is at most one flattened field.
+fn serialize_gap(
+    fields: &[parse::Field],
+    after: Option<(bool, u64)>,
+    before: Option<(bool, u64)>,
+) -> proc_macro2::TokenStream {
+    let flattened: Vec<_> = fields
+        .iter()
+        .filter(|field| field.flatten)
+        .map(|field| &field.member)
+        .collect();
+    let Some(unknown) = fields.iter().find(|field| field.unknown) else {
+        let filter = rank_filter(after, before, None);
+        return quote! {
+            #(self.#flattened.__serde_indexed_serialize_entries(&mut map, &#filter)?;)*
+        };
+    };
+    let unknown = &unknown.member;
+    let after = option_rank_literal(after);
+    let before = option_rank_literal(before);
+    let serialize_flattened = if flattened.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            let __serde_indexed_before = match __serde_indexed_next {
+                Some((rank, _, _)) => Some(rank),
+                None => __serde_indexed_end,
+            };
+            let filter = |key: i64| {
+                let rank = __serde_indexed_rank(key);
+                __serde_indexed_after < Some(rank)
+                    && (__serde_indexed_before.is_none() || Some(rank) < __serde_indexed_before)
+            };
+            #(self.#flattened.__serde_indexed_serialize_entries(&mut map, &filter)?;)*
+        }
+    };
+    quote! {{
+        let mut __serde_indexed_after: Option<(bool, u64)> = #after;
+        let __serde_indexed_end: Option<(bool, u64)> = #before;
+        loop {
+            let __serde_indexed_next = IntoIterator::into_iter(&self.#unknown)
+                .map(|(key, value)| (__serde_indexed_rank(*key), key, value))
+                .filter(|&(rank, _, _)| {
+                    __serde_indexed_after < Some(rank)
+                        && (__serde_indexed_end.is_none() || Some(rank) < __serde_indexed_end)
+                })
+                .min_by_key(|&(rank, _, _)| rank);
+            #serialize_flattened
+            let Some((rank, key, value)) = __serde_indexed_next else {
+                break;
+            };
+            map.serialize_entry(key, value)?;
+            __serde_indexed_after = Some(rank);
+        }
+    }}
+}
+
+/// Serializes a field as an entry of the map, unless it is skipped by `skip_serializing_if`.
+fn serialize_field(
+    field: &parse::Field,
+    keys: Keys,
+    impl_generics_serialize: &ImplGenerics<'_>,
+    ty_generics_serialize: &TypeGenerics<'_>,
+    ty_generics: &TypeGenerics<'_>,
+    where_clause: Option<&WhereClause>,
+    ident: &Ident,
+) -> proc_macro2::TokenStream {
+    let member = &field.member;
+    let serialize_member = serialize_member(
+        field,
+        impl_generics_serialize,
+        ty_generics_serialize,
+        ty_generics,
+        where_clause,
+        ident,
+    );
+
+    // println!("field {:?} index {:?}", &field.label, field.index);
+    let serialize_entry = keys.serialize_entry(field, serialize_member);
+    match &field.skip_serializing_if {
+        Skip::If(path) => quote! {
+            if !#path(&self.#member) {
+                #serialize_entry
+            }
+        },
+        Skip::Always => unreachable!(),
+        Skip::Never => serialize_entry,
+    }
+}
+
+/// Fields that are serialized with their index, sorted in canonical order.
+fn indexed_fields(fields: &[parse::Field], keys: Keys) -> Vec<&parse::Field> {
+    let mut indexed_fields: Vec<_> = fields
+        .iter()
+        .filter(|field| field.is_serialized())
+        .collect();
+    // Always serialize the fields in canonical order, independent of the declaration order
+    indexed_fields.sort_by(|a, b| keys.canonical_order(a, b));
+    indexed_fields
+}
 
 fn serialize_fields(
     fields: &[parse::Field],
-    offset: usize,
-    impl_generics_serialize: ImplGenerics<'_>,
-    ty_generics_serialize: TypeGenerics<'_>,
+    keys: Keys,
+    impl_generics_serialize: &ImplGenerics<'_>,
+    ty_generics_serialize: &TypeGenerics<'_>,
     ty_generics: &TypeGenerics<'_>,
     where_clause: Option<&WhereClause>,
     ident: &Ident,
 ) -> Vec<proc_macro2::TokenStream> {
-    fields
-        .iter()
-        .filter(|field| !field.skip_serializing_if.is_always())
-        .map(|field| {
-            // index should only be none if the field is always skipped, so this should never panic
-            let index = field.index.expect("index must be set for fields that are not skipped") + offset;
+    let mut entries = Vec::new();
+    let mut previous_rank = None;
+    for field in indexed_fields(fields, keys) {
+        // Text indices are sorted after the integer indices and cannot be used together with
+        // flattened fields
+        if let Some(index) = field.index {
+            let rank = canonical_rank(index + keys.offset);
+            entries.push(serialize_gap(fields, previous_rank, Some(rank)));
+            previous_rank = Some(rank);
+        }
+        entries.push(serialize_field(
+            field,
+            keys,
+            impl_generics_serialize,
+            ty_generics_serialize,
+            ty_generics,
+            where_clause,
+            ident,
+        ));
+    }
+    entries.push(serialize_gap(fields, previous_rank, None));
+    entries
+}
+
+/// Defines the hidden methods that are used to serialize this struct as a flattened field.
+///
+/// The entries of the catch-all field for unknown entries are not serialized.  Structs with text
+/// indices cannot be flattened as the keys are filtered as `i64`.
+fn serialize_flatten_methods(
+    input: &Input,
+    fields: &[parse::Field],
+    impl_generics: &ImplGenerics<'_>,
+    impl_generics_serialize: &ImplGenerics<'_>,
+    ty_generics_serialize: &TypeGenerics<'_>,
+    ty_generics: &TypeGenerics<'_>,
+    where_clause: Option<&WhereClause>,
+) -> proc_macro2::TokenStream {
+    if fields.iter().any(|field| field.text_index.is_some()) {
+        return quote! {};
+    }
+    let ident = &input.ident;
+    let vis = &input.vis;
+    // The keys are passed to the filter of the parent struct as `i64`
+    let keys = Keys {
+        signed: true,
+        human_readable: false,
+        ..Keys::new(fields, &input.attrs)
+    };
+    let num_fields = count_serialized_fields(fields.iter().filter(|field| !field.unknown));
+    let flattened = fields.iter().find(|field| field.flatten);
+    // The entries of a nested flattened field are serialized between the entries of this struct,
+    // restricted to the keys that are accepted by the filter of the parent struct
+    let within = quote!(filter);
+    let serialize_flattened = |after, before| {
+        flattened.map(|field| {
             let member = &field.member;
-            let serialize_member = match &field.serialize_with {
-                None => quote!(&self.#member),
-                Some(f) => {
-                    let ty = &field.ty;
-                    quote!({
-                            struct __InternalSerdeIndexedSerializeWith #impl_generics_serialize {
-                                value: &'__serde_indexed_lifetime #ty,
-                                phantom: ::core::marker::PhantomData<#ident #ty_generics>,
-                            }
+            let filter = rank_filter(after, before, Some(&within));
+            quote! {
+                self.#member.__serde_indexed_serialize_entries(map, &#filter)?;
+            }
+        })
+    };
+    let mut entries = Vec::new();
+    let mut previous_rank = None;
+    for field in indexed_fields(fields, keys) {
+        let index = keys.literal(field);
+        let rank = canonical_rank(keys.index(field));
+        entries.extend(serialize_flattened(previous_rank, Some(rank)));
+        previous_rank = Some(rank);
+        let entry = serialize_field(
+            field,
+            keys,
+            impl_generics_serialize,
+            ty_generics_serialize,
+            ty_generics,
+            where_clause,
+            ident,
+        );
+        entries.push(quote! {
+            if filter(#index) {
+                #entry
+            }
+        });
+    }
+    entries.extend(serialize_flattened(previous_rank, None));
+    let rank = if flattened.is_some() {
+        rank_function()
+    } else {
+        quote! {}
+    };
 
-                            impl #impl_generics_serialize serde::Serialize for __InternalSerdeIndexedSerializeWith #ty_generics_serialize #where_clause {
-                                fn serialize<__S>(
-                                    &self,
-                                    __s: __S,
-                                ) -> ::core::result::Result<__S::Ok, __S::Error>
-                                where
-                                    __S: serde::Serializer,
-                                {
-                                    #f(self.value, __s)
-                                }
-                            }
+    quote! {
+        #[automatically_derived]
+        #[allow(dead_code)]
+        impl #impl_generics #ident #ty_generics #where_clause {
+            #[doc(hidden)]
+            #vis fn __serde_indexed_len(&self) -> usize {
+                0 #( + #num_fields)*
+            }
 
-                            &__InternalSerdeIndexedSerializeWith { value: &self.#member, phantom: ::core::marker::PhantomData::<#ident #ty_generics> }
-                    })
-                }
-            };
+            #[doc(hidden)]
+            #vis fn __serde_indexed_serialize_entries<__M, __F>(
+                &self,
+                map: &mut __M,
+                filter: &__F,
+            ) -> core::result::Result<(), __M::Error>
+            where
+                __M: serde::ser::SerializeMap,
+                __F: Fn(i64) -> bool,
+            {
+                use serde::ser::SerializeMap;
 
-            // println!("field {:?} index {:?}", &field.label, field.index);
-            match &field.skip_serializing_if {
-                Skip::If(path) => quote! {
-                    if !#path(&self.#member) {
-                        map.serialize_entry(&#index, #serialize_member)?;
-                    }
-                },
-                Skip::Always => unreachable!(),
-                Skip::Never => quote! {
-                    map.serialize_entry(&#index, #serialize_member)?;
-                },
+                #rank
+
+                #(#entries)*
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Fields that are not skipped, sorted by their index, i. e. their position in array mode.
+fn array_fields(fields: &[parse::Field]) -> Vec<&parse::Field> {
+    let mut array_fields: Vec<_> = fields
+        .iter()
+        .filter(|field| !field.skip_serializing_if.is_always())
+        .collect();
+    array_fields.sort_by_key(|field| field.index);
+    array_fields
+}
+
+fn serialize_elements(
+    fields: &[parse::Field],
+    impl_generics_serialize: &ImplGenerics<'_>,
+    ty_generics_serialize: &TypeGenerics<'_>,
+    ty_generics: &TypeGenerics<'_>,
+    where_clause: Option<&WhereClause>,
+    ident: &Ident,
+) -> Vec<proc_macro2::TokenStream> {
+    array_fields(fields)
+        .into_iter()
+        .map(|field| {
+            let serialize_member = serialize_member(
+                field,
+                impl_generics_serialize,
+                ty_generics_serialize,
+                ty_generics,
+                where_clause,
+                ident,
+            );
+            quote! {
+                seq.serialize_element(#serialize_member)?;
             }
         })
         .collect()
 }
 
-fn count_serialized_fields(fields: &[parse::Field]) -> Vec<proc_macro2::TokenStream> {
+fn count_serialized_fields<'a>(
+    fields: impl IntoIterator<Item = &'a parse::Field>,
+) -> Vec<proc_macro2::TokenStream> {
     fields
-        .iter()
+        .into_iter()
         .map(|field| {
             // let index = field.index + offset;
             let member = &field.member;
+            if field.unknown {
+                return quote! { (&self.#member).into_iter().count() };
+            }
+            if field.flatten {
+                return quote! { self.#member.__serde_indexed_len() };
+            }
+            if field.skip_serializing {
+                return quote! { 0 };
+            }
             match &field.skip_serializing_if {
                 Skip::If(path) => {
                     quote! { if #path(&self.#member) { 0 } else { 1 } }
@@ -233,22 +989,239 @@ fn count_serialized_fields(fields: &[parse::Field]) -> Vec<proc_macro2::TokenStr
         .collect()
 }
 
-#[proc_macro_derive(SerializeIndexed, attributes(serde, serde_indexed))]
-pub fn derive_serialize(input: TokenStream) -> TokenStream {
-    let input = parse_macro_input!(input as Input);
-    let ident = input.ident;
-    let num_fields = count_serialized_fields(&input.fields);
-    let (_, ty_generics, where_clause) = input.generics.split_for_impl();
-    let mut generics_cl = input.generics.clone();
-    generics_cl.type_params_mut().for_each(|t| {
-        t.bounds
-            .push_value(TypeParamBound::Verbatim(quote!(serde::Serialize)));
-    });
-    let (impl_generics, _, _) = generics_cl.split_for_impl();
-
-    let mut generics_cl2 = generics_cl.clone();
+/// Collects the type parameters used by a type, like serde_derive does for its bound inference.
+///
+/// Paths starting with a type parameter like `T::Assoc` count as a use of `T`, while the type
+/// parameters of `PhantomData<T>` are ignored because it implements the serde traits for any `T`.
+struct TypeParamVisitor<'a> {
+    params: Vec<&'a Ident>,
+    used: Vec<&'a Ident>,
+    /// Associated types of type parameters, e. g. `T::Assoc`, which are bound instead of `T`.
+    associated: Vec<syn::TypePath>,
+}
 
-    generics_cl2
+impl<'ast> syn::visit::Visit<'ast> for TypeParamVisitor<'_> {
+    fn visit_type_path(&mut self, ty: &'ast syn::TypePath) {
+        let path = &ty.path;
+        if ty.qself.is_none()
+            && path.leading_colon.is_none()
+            && path.segments.len() > 1
+            && self
+                .params
+                .iter()
+                .any(|param| *param == &path.segments[0].ident)
+            && !self.associated.iter().any(|associated| {
+                associated.to_token_stream().to_string() == ty.to_token_stream().to_string()
+            })
+        {
+            self.associated.push(ty.clone());
+        }
+        syn::visit::visit_type_path(self, ty);
+    }
+
+    fn visit_path(&mut self, path: &'ast syn::Path) {
+        if path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "PhantomData")
+        {
+            return;
+        }
+        // Only the type parameter itself is bound, not the type parameter of `T::Assoc`
+        if let (None, 1, Some(segment)) = (
+            &path.leading_colon,
+            path.segments.len(),
+            path.segments.first(),
+        ) {
+            if let Some(param) = self.params.iter().find(|param| **param == &segment.ident) {
+                if !self.used.contains(param) {
+                    self.used.push(param);
+                }
+            }
+        }
+        syn::visit::visit_path(self, path);
+    }
+}
+
+/// Adds the where predicates required to (de)serialize the fields to the generics.
+///
+/// The `bound` attribute of the container replaces all inferred bounds.  Otherwise, the predicates
+/// of the fields with a `bound` attribute are added, and every type parameter used by any other
+/// field that is (de)serialized without a `with` function is bound by `trait_bound`.
+fn add_bounds<'a>(
+    generics: &mut Generics,
+    container_bound: Option<&Vec<WherePredicate>>,
+    fields: impl IntoIterator<Item = &'a parse::Field> + Clone,
+    field_bound: impl Fn(&Bounds) -> Option<&Vec<WherePredicate>>,
+    is_inferred: impl Fn(&parse::Field) -> bool,
+    trait_bound: proc_macro2::TokenStream,
+) {
+    let predicates: Vec<WherePredicate> = match container_bound {
+        Some(predicates) => predicates.clone(),
+        None => {
+            let mut predicates: Vec<WherePredicate> = fields
+                .clone()
+                .into_iter()
+                .filter_map(|field| field_bound(&field.bound))
+                .flatten()
+                .cloned()
+                .collect();
+            predicates.extend(inferred_bounds(
+                generics,
+                fields,
+                field_bound,
+                is_inferred,
+                trait_bound,
+            ));
+            predicates
+        }
+    };
+    if !predicates.is_empty() {
+        generics.make_where_clause().predicates.extend(predicates);
+    }
+}
+
+/// Bounds every type parameter used by the fields selected by `is_inferred` by `trait_bound`,
+/// skipping fields with a `bound` attribute.
+fn inferred_bounds<'a>(
+    generics: &Generics,
+    fields: impl IntoIterator<Item = &'a parse::Field>,
+    field_bound: impl Fn(&Bounds) -> Option<&Vec<WherePredicate>>,
+    is_inferred: impl Fn(&parse::Field) -> bool,
+    trait_bound: proc_macro2::TokenStream,
+) -> Vec<WherePredicate> {
+    let mut visitor = TypeParamVisitor {
+        params: generics.type_params().map(|param| &param.ident).collect(),
+        used: Vec::new(),
+        associated: Vec::new(),
+    };
+    for field in fields {
+        if field_bound(&field.bound).is_none() && is_inferred(field) {
+            syn::visit::visit_type(&mut visitor, &field.ty);
+        }
+    }
+    visitor
+        .used
+        .iter()
+        .map(|ident| -> WherePredicate { parse_quote!(#ident: #trait_bound) })
+        .chain(
+            visitor
+                .associated
+                .iter()
+                .map(|ty| -> WherePredicate { parse_quote!(#ty: #trait_bound) }),
+        )
+        .collect()
+}
+
+/// Adds the `Serialize` bounds to the generics of the input.
+fn generics_with_ser<'a>(
+    input: &Input,
+    fields: impl IntoIterator<Item = &'a parse::Field> + Clone,
+) -> Generics {
+    let mut generics_cl = input.generics.clone();
+    add_bounds(
+        &mut generics_cl,
+        input.attrs.bound.serialize.as_ref(),
+        fields,
+        |bounds| bounds.serialize.as_ref(),
+        |field| {
+            (field.is_serialized() || field.unknown || field.flatten)
+                && field.serialize_with.is_none()
+        },
+        quote!(serde::Serialize),
+    );
+    generics_cl
+}
+
+/// Makes the generated code use the serde crate at the path given by the `crate` attribute.
+///
+/// The generated code refers to the `serde` crate by name, so the path is imported as `serde` in a
+/// scope that only contains the generated implementation.
+fn with_serde_path(
+    input: &Input,
+    implementation: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    match &input.attrs.serde_path {
+        Some(path) => quote! {
+            const _: () = {
+                use #path as serde;
+
+                #implementation
+            };
+        },
+        None => implementation,
+    }
+}
+
+#[proc_macro_derive(SerializeIndexed, attributes(serde, serde_indexed))]
+pub fn derive_serialize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as Input);
+    TokenStream::from(with_serde_path(
+        &input,
+        match &input.data {
+            _ if input.attrs.into.is_some() => serialize_into(&input),
+            Data::Struct(fields) => serialize_struct(&input, fields),
+            Data::Newtype(field) => serialize_newtype(&input, field),
+            Data::Enum(variants) => match input.attrs.tag {
+                Some(tag) => serialize_tagged_enum(&input, tag, variants),
+                None => serialize_enum(&input, variants),
+            },
+        },
+    ))
+}
+
+/// Serializes the value by converting a clone of it into the type given by the `into` attribute.
+fn serialize_into(input: &Input) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let into = &input.attrs.into;
+    let generics_cl = generics_with_ser(input, &[]);
+    let (impl_generics, ty_generics, where_clause) = generics_cl.split_for_impl();
+
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics serde::Serialize for #ident #ty_generics #where_clause  {
+            fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer
+            {
+                let value: #into = core::convert::Into::into(core::clone::Clone::clone(self));
+                serde::Serialize::serialize(&value, serializer)
+            }
+        }
+    }
+}
+
+fn serialize_newtype(input: &Input, field: &parse::Field) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let generics_cl = generics_with_ser(input, core::slice::from_ref(field));
+    let (impl_generics, ty_generics, where_clause) = generics_cl.split_for_impl();
+
+    let body = match &field.serialize_with {
+        None => quote!(serde::Serialize::serialize(&self.0, serializer)),
+        Some(f) => quote!(#f(&self.0, serializer)),
+    };
+
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics serde::Serialize for #ident #ty_generics #where_clause  {
+            fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer
+            {
+                #body
+            }
+        }
+    }
+}
+
+fn serialize_struct(input: &Input, fields: &[parse::Field]) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let generics_cl = generics_with_ser(input, fields);
+    let (impl_generics, ty_generics, where_clause) = generics_cl.split_for_impl();
+
+    let mut generics_cl2 = generics_cl.clone();
+
+    generics_cl2
         .params
         .push(syn::GenericParam::Lifetime(LifetimeParam::new(
             Lifetime::new("'__serde_indexed_lifetime", Span::call_site()),
@@ -256,74 +1229,457 @@ pub fn derive_serialize(input: TokenStream) -> TokenStream {
 
     let (impl_generics_serialize, ty_generics_serialize, _) = generics_cl2.split_for_impl();
 
-    let serialize_fields = serialize_fields(
-        &input.fields,
-        input.attrs.offset,
-        impl_generics_serialize,
-        ty_generics_serialize,
-        &ty_generics,
-        where_clause,
-        &ident,
-    );
+    let mut flatten_methods = quote! {};
+    let body = if input.attrs.array {
+        let serialize_elements = serialize_elements(
+            fields,
+            &impl_generics_serialize,
+            &ty_generics_serialize,
+            &ty_generics,
+            where_clause,
+            ident,
+        );
+        let len = serialize_elements.len();
+        quote! {
+            use serde::ser::SerializeTuple;
+            let mut seq = serializer.serialize_tuple(#len)?;
+
+            #(#serialize_elements)*
 
-    TokenStream::from(quote! {
+            seq.end()
+        }
+    } else {
+        let num_fields = count_serialized_fields(fields);
+        let human_readable = if input.attrs.human_readable {
+            quote! {
+                let __serde_indexed_human_readable = serializer.is_human_readable();
+            }
+        } else {
+            quote! {}
+        };
+        let rank = if fields.iter().any(|field| field.flatten || field.unknown) {
+            rank_function()
+        } else {
+            quote! {}
+        };
+        let serialize_fields = serialize_fields(
+            fields,
+            Keys::new(fields, &input.attrs),
+            &impl_generics_serialize,
+            &ty_generics_serialize,
+            &ty_generics,
+            where_clause,
+            ident,
+        );
+        flatten_methods = serialize_flatten_methods(
+            input,
+            fields,
+            &impl_generics,
+            &impl_generics_serialize,
+            &ty_generics_serialize,
+            &ty_generics,
+            where_clause,
+        );
+        quote! {
+            use serde::ser::SerializeMap;
+            let num_fields = 0 #( + #num_fields)*;
+            #human_readable
+            #rank
+            let mut map = serializer.serialize_map(Some(num_fields))?;
+
+            #(#serialize_fields)*
+
+            map.end()
+        }
+    };
+
+    quote! {
         #[automatically_derived]
         impl #impl_generics serde::Serialize for #ident #ty_generics #where_clause  {
+            fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer
+            {
+                #body
+            }
+        }
+
+        #flatten_methods
+    }
+}
+
+fn serialize_enum(input: &Input, variants: &[parse::Variant]) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let generics_cl = generics_with_ser(
+        input,
+        variants.iter().filter_map(|variant| variant.field.as_ref()),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics_cl.split_for_impl();
+
+    // Prefer unsigned integers as long as no negative indices are used, like for struct keys.
+    // The value of an `other` variant can be negative, so all values are signed in that case.
+    let signed = variants
+        .iter()
+        .any(|variant| variant.other || variant.index < 0);
+    let serialize_index = if signed {
+        quote!(serialize_i64)
+    } else {
+        quote!(serialize_u64)
+    };
+
+    let match_variants = variants.iter().map(|variant| {
+        let variant_ident = &variant.ident;
+        let span = variant.original_span;
+        if variant.other {
+            quote_spanned! { span =>
+                #ident::#variant_ident(value) => {
+                    let value: i64 = value;
+                    serializer.serialize_i64(value)
+                }
+            }
+        } else if variant.field.is_some() {
+            let index = if signed {
+                Literal::i64_suffixed(variant.index)
+            } else {
+                Literal::u64_suffixed(variant.index as u64)
+            };
+            quote_spanned! { span =>
+                #ident::#variant_ident(ref __serde_indexed_value) => {
+                    let mut map = serializer.serialize_map(Some(1))?;
+                    map.serialize_entry(&#index, __serde_indexed_value)?;
+                    map.end()
+                }
+            }
+        } else {
+            let index = Literal::i64_unsuffixed(variant.index);
+            quote_spanned! { span =>
+                #ident::#variant_ident => serializer.#serialize_index(#index),
+            }
+        }
+    });
+
+    let use_serialize_map = if variants.iter().any(|variant| variant.field.is_some()) {
+        quote!(
+            use serde::ser::SerializeMap;
+        )
+    } else {
+        quote! {}
+    };
+
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics serde::Serialize for #ident #ty_generics #where_clause {
+            fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer
+            {
+                #use_serialize_map
+
+                match *self {
+                    #(#match_variants)*
+                }
+            }
+        }
+    }
+}
+
+/// Serializes the tag entry and the entries of the field of the variant in one map.
+///
+/// The field is serialized like a flattened field, so the tag entry is placed in canonical order,
+/// unless `tag_first` is set.
+fn serialize_tagged_enum(
+    input: &Input,
+    tag: i64,
+    variants: &[parse::Variant],
+) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let generics_cl = generics_with_ser(
+        input,
+        variants.iter().filter_map(|variant| variant.field.as_ref()),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics_cl.split_for_impl();
+
+    let tag_key = Literal::i64_suffixed(tag);
+    // With `tag_first`, the entries of the field that sort before the tag are serialized after it
+    let (serialize_before_tag, after_tag) = if input.attrs.tag_first {
+        (quote! {}, quote!(|_: i64| true))
+    } else {
+        let before_tag = rank_filter(None, Some(canonical_rank(tag)), None);
+        (
+            quote! {
+                __serde_indexed_value.__serde_indexed_serialize_entries(&mut map, &#before_tag)?;
+            },
+            rank_filter(Some(canonical_rank(tag)), None, None),
+        )
+    };
+    let rank_function =
+        if !input.attrs.tag_first && variants.iter().any(|variant| variant.field.is_some()) {
+            rank_function()
+        } else {
+            quote! {}
+        };
+
+    let match_variants = variants.iter().map(|variant| {
+        let variant_ident = &variant.ident;
+        let tag_value = Literal::i64_suffixed(variant.index);
+        let span = variant.original_span;
+        if variant.field.is_some() {
+            quote_spanned! { span =>
+                #ident::#variant_ident(ref __serde_indexed_value) => {
+                    let mut map =
+                        serializer.serialize_map(Some(1 + __serde_indexed_value.__serde_indexed_len()))?;
+                    #serialize_before_tag
+                    map.serialize_entry(&#tag_key, &#tag_value)?;
+                    __serde_indexed_value.__serde_indexed_serialize_entries(&mut map, &#after_tag)?;
+                    map.end()
+                }
+            }
+        } else {
+            quote_spanned! { span =>
+                #ident::#variant_ident => {
+                    let mut map = serializer.serialize_map(Some(1))?;
+                    map.serialize_entry(&#tag_key, &#tag_value)?;
+                    map.end()
+                }
+            }
+        }
+    });
+
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics serde::Serialize for #ident #ty_generics #where_clause {
             fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
             where
                 S: serde::Serializer
             {
                 use serde::ser::SerializeMap;
-                let num_fields = 0 #( + #num_fields)*;
-                let mut map = serializer.serialize_map(Some(num_fields))?;
 
-                #(#serialize_fields)*
+                #rank_function
 
-                map.end()
+                match *self {
+                    #(#match_variants)*
+                }
             }
         }
-    })
+    }
 }
 
 fn none_fields(fields: &[parse::Field]) -> Vec<proc_macro2::TokenStream> {
     fields
         .iter()
-        .filter(|f| !f.skip_serializing_if.is_always())
+        .filter(|field| field.is_deserialized() || field.unknown || field.flatten)
         .map(|field| {
-            let ident = format_ident!("{}", &field.label);
+            let ident = field.binding();
             let span = field.original_span;
-            quote_spanned! { span =>
-                let mut #ident = None;
+            if field.flatten {
+                let ty = &field.ty;
+                quote_spanned! { span =>
+                    let mut #ident = <#ty>::__serde_indexed_state();
+                }
+            } else if field.unknown {
+                let ty = &field.ty;
+                quote_spanned! { span =>
+                    let mut #ident: #ty = ::core::default::Default::default();
+                }
+            } else {
+                quote_spanned! { span =>
+                    let mut #ident = None;
+                }
             }
         })
         .collect()
 }
 
-fn unwrap_expected_fields(fields: &[parse::Field]) -> Vec<proc_macro2::TokenStream> {
+/// Inserts the current entry into the catch-all field for unknown entries.
+///
+/// The field must have an `insert` method returning either `Option<V>` like `BTreeMap` or
+/// `Result<Option<V>, (K, V)>` like `heapless::LinearMap`.
+fn insert_unknown(field: &parse::Field) -> proc_macro2::TokenStream {
+    let ident = field.binding();
+    quote! {
+        trait __SerdeIndexedInsertResult {
+            fn check(self) -> core::result::Result<(), &'static str>;
+        }
+
+        impl<T> __SerdeIndexedInsertResult for Option<T> {
+            fn check(self) -> core::result::Result<(), &'static str> {
+                match self {
+                    None => Ok(()),
+                    Some(_) => Err("duplicate index"),
+                }
+            }
+        }
+
+        impl<T, E> __SerdeIndexedInsertResult for core::result::Result<Option<T>, E> {
+            fn check(self) -> core::result::Result<(), &'static str> {
+                match self {
+                    Ok(None) => Ok(()),
+                    Ok(Some(_)) => Err("duplicate index"),
+                    Err(_) => Err("too many unknown entries to insert index"),
+                }
+            }
+        }
+
+        let value = map.next_value()?;
+        let result = #ident.insert(__serde_indexed_internal_key, value);
+        if let Err(error) = __SerdeIndexedInsertResult::check(result) {
+            return Err(serde::de::Error::custom(format_args!(
+                "{} `{}`",
+                error, __serde_indexed_internal_key,
+            )));
+        }
+    }
+}
+
+/// Binds `__serde_indexed_default` to the container default value, if any is set.
+///
+/// `ty` is the type of the container, i. e. `Self::Value` in the visitor.
+fn container_default(
+    default: &DefaultValue,
+    ty: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    match default {
+        DefaultValue::None => quote! {},
+        DefaultValue::Default => quote! {
+            let __serde_indexed_default: #ty = ::core::default::Default::default();
+        },
+        DefaultValue::Path(path) => quote! {
+            let __serde_indexed_default: #ty = #path();
+        },
+    }
+}
+
+/// The expression for the default value of a field, if any is set.
+///
+/// The default value of the field takes precedence over the container default value.
+fn default_value(
+    field: &parse::Field,
+    container_default: &DefaultValue,
+) -> Option<proc_macro2::TokenStream> {
+    let span = field.original_span;
+    let member = &field.member;
+    match (&field.default, container_default) {
+        (DefaultValue::Default, _) => {
+            Some(quote_spanned!(span => ::core::default::Default::default()))
+        }
+        (DefaultValue::Path(path), _) => Some(quote_spanned!(span => #path())),
+        (DefaultValue::None, DefaultValue::None) => None,
+        (DefaultValue::None, _) => Some(quote_spanned!(span => __serde_indexed_default.#member)),
+    }
+}
+
+fn unwrap_expected_fields(
+    fields: &[parse::Field],
+    container_default: &DefaultValue,
+) -> Vec<proc_macro2::TokenStream> {
     fields
         .iter()
+        .filter(|field| !field.unknown)
         .map(|field| {
             let label = field.label.clone();
-            let ident = format_ident!("{}", &field.label);
+            let ident = field.binding();
             let span = field.original_span;
-            match field.skip_serializing_if {
-                Skip::Never => quote! {
+            if field.flatten {
+                let ty = &field.ty;
+                return quote_spanned! { span =>
+                    let #ident = <#ty>::__serde_indexed_finish(#ident)?;
+                };
+            }
+            let default = default_value(field, container_default);
+            if !field.is_deserialized() {
+                let default =
+                    default.unwrap_or_else(|| quote!(::core::default::Default::default()));
+                return quote! {
+                    let #ident = #default;
+                };
+            }
+            match (&field.skip_serializing_if, default) {
+                (Skip::Always, _) => unreachable!(),
+                (_, Some(default)) => quote_spanned! { span =>
+                    let #ident = match #ident {
+                        Some(value) => value,
+                        None => #default,
+                    };
+                },
+                (Skip::Never, None) => quote! {
                     let #ident = #ident.ok_or_else(|| serde::de::Error::missing_field(#label))?;
                 },
-                Skip::If(_) => quote_spanned! { span =>
+                (Skip::If(_), None) => quote_spanned! { span =>
                     let #ident = #ident.unwrap_or_default();
                 },
-                Skip::Always => quote! {
-                    let #ident = ::core::default::Default::default();
-                },
             }
         })
         .collect()
 }
 
+/// Checks a deserialized value with the `validate` function of the field, if any.
+///
+/// The error message contains the label of the field and the given location, i. e. its index or
+/// its position in array mode.
+fn validate_field(
+    field: &parse::Field,
+    value: &Ident,
+    location: String,
+) -> proc_macro2::TokenStream {
+    match &field.validate {
+        None => quote! {},
+        Some(path) => {
+            let message = format!(
+                "invalid value for field `{}` at {location}: {{}}",
+                field.label
+            );
+            let span = field.original_span;
+            let ty = &field.ty;
+            // The type annotation allows deref coercions, e. g. `&String` to `&str`
+            quote_spanned! { span =>
+                let #value: #ty = #value;
+                if let Err(error) = #path(&#value) {
+                    return Err(serde::de::Error::custom(format_args!(#message, error)));
+                }
+            }
+        }
+    }
+}
+
+/// Defines `__InternalSerdeIndexedDeserializeWith`, which deserializes a field using its `deserialize_with` function.
+fn deserialize_with(
+    field: &parse::Field,
+    f: &syn::ExprPath,
+    impl_generics_with_de: &ImplGenerics<'_>,
+    ty_generics: &TypeGenerics<'_>,
+    ty_generics_with_de: &TypeGenerics<'_>,
+    where_clause: Option<&WhereClause>,
+    struct_ident: &Ident,
+) -> proc_macro2::TokenStream {
+    let ty = &field.ty;
+    let span = field.original_span;
+    quote_spanned!(span =>
+        struct __InternalSerdeIndexedDeserializeWith #impl_generics_with_de {
+            value: #ty,
+            phantom: ::core::marker::PhantomData<#struct_ident #ty_generics>,
+            lifetime: ::core::marker::PhantomData<&'de ()>,
+        }
+        impl #impl_generics_with_de serde::Deserialize<'de> for __InternalSerdeIndexedDeserializeWith #ty_generics_with_de #where_clause {
+            fn deserialize<__D>(
+                __deserializer: __D,
+            ) -> Result<Self, __D::Error>
+            where
+                __D: serde::Deserializer<'de>,
+            {
+
+                Ok(__InternalSerdeIndexedDeserializeWith {
+                    value: #f(__deserializer)?,
+                    phantom: ::core::marker::PhantomData,
+                    lifetime: ::core::marker::PhantomData,
+                })
+            }
+        }
+    )
+}
+
 fn match_fields(
     fields: &[parse::Field],
-    offset: usize,
+    keys: Keys,
     impl_generics_with_de: &ImplGenerics<'_>,
     ty_generics: &TypeGenerics<'_>,
     ty_generics_with_de: &TypeGenerics<'_>,
@@ -332,40 +1688,33 @@ fn match_fields(
 ) -> Vec<proc_macro2::TokenStream> {
     fields
         .iter()
-        .filter(|f| !f.skip_serializing_if.is_always())
+        .filter(|field| field.is_deserialized())
         .map(|field| {
             let label = field.label.clone();
-            let ident = format_ident!("{}", &field.label);
-            // index should only be none if the field is always skipped, so this should never panic
-            let index = field.index.expect("index must be set for fields that are not skipped") + offset;
+            let ident = field.binding();
+            let pattern = keys.pattern(field);
             let span = field.original_span;
+            let validate = validate_field(
+                field,
+                &format_ident!("next_value"),
+                format!("index `{}`", keys.display(field)),
+            );
 
             let next_value = match &field.deserialize_with {
                 Some(f) => {
-                    let ty = &field.ty;
+                    let deserialize_with = deserialize_with(
+                        field,
+                        f,
+                        impl_generics_with_de,
+                        ty_generics,
+                        ty_generics_with_de,
+                        where_clause,
+                        struct_ident,
+                    );
                     quote_spanned!(span => {
-                            struct __InternalSerdeIndexedDeserializeWith #impl_generics_with_de {
-                                value: #ty,
-                                phantom: ::core::marker::PhantomData<#struct_ident #ty_generics>,
-                                lifetime: ::core::marker::PhantomData<&'de ()>,
-                            }
-                            impl #impl_generics_with_de serde::Deserialize<'de> for __InternalSerdeIndexedDeserializeWith #ty_generics_with_de #where_clause {
-                                fn deserialize<__D>(
-                                    __deserializer: __D,
-                                ) -> Result<Self, __D::Error>
-                                where
-                                    __D: serde::Deserializer<'de>,
-                                {
-
-                                    Ok(__InternalSerdeIndexedDeserializeWith {
-                                        value: #f(__deserializer)?,
-                                        phantom: ::core::marker::PhantomData,
-                                        lifetime: ::core::marker::PhantomData,
-                                    })
-                                }
-                            }
+                            #deserialize_with
 
-                            let __InternalSerdeIndexedDeserializeWith { value, lifetime: _, phantom: _ } = map.next_value()?;
+                            let __InternalSerdeIndexedDeserializeWith { value, lifetime: _, phantom: _ }: __InternalSerdeIndexedDeserializeWith #ty_generics_with_de = map.next_value()?;
                             value
                         }
                     )
@@ -374,11 +1723,12 @@ fn match_fields(
             };
 
             quote_spanned!{ span =>
-                #index => {
+                #pattern => {
                     if #ident.is_some() {
                         return Err(serde::de::Error::duplicate_field(#label));
                     }
                     let next_value = #next_value;
+                    #validate
                     #ident = Some(next_value);
                 },
             }
@@ -386,116 +1736,1190 @@ fn match_fields(
         .collect()
 }
 
-fn all_fields(fields: &[parse::Field]) -> Vec<proc_macro2::TokenStream> {
-    fields
-        .iter()
-        .map(|field| {
-            let ident = format_ident!("{}", &field.label);
+fn next_elements(
+    fields: &[parse::Field],
+    container_default: &DefaultValue,
+    impl_generics_with_de: &ImplGenerics<'_>,
+    ty_generics: &TypeGenerics<'_>,
+    ty_generics_with_de: &TypeGenerics<'_>,
+    where_clause: Option<&WhereClause>,
+    struct_ident: &Ident,
+) -> Vec<proc_macro2::TokenStream> {
+    let elements = array_fields(fields)
+        .into_iter()
+        .enumerate()
+        .map(|(position, field)| {
+            let ident = field.binding();
             let span = field.original_span;
-            quote_spanned! { span =>
-                #ident
-            }
-        })
-        .collect()
-}
-
-#[proc_macro_derive(DeserializeIndexed, attributes(serde, serde_indexed))]
-pub fn derive_deserialize(input: TokenStream) -> TokenStream {
-    let input = parse_macro_input!(input as Input);
-    let ident = input.ident;
-    let none_fields = none_fields(&input.fields);
-    let unwrap_expected_fields = unwrap_expected_fields(&input.fields);
-    let all_fields = all_fields(&input.fields);
 
-    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+            let next_element = match &field.deserialize_with {
+                Some(f) => {
+                    let deserialize_with = deserialize_with(
+                        field,
+                        f,
+                        impl_generics_with_de,
+                        ty_generics,
+                        ty_generics_with_de,
+                        where_clause,
+                        struct_ident,
+                    );
+                    quote_spanned!(span => {
+                            #deserialize_with
 
-    let mut generics_cl = input.generics.clone();
-    generics_cl.params.insert(
-        0,
-        syn::GenericParam::Lifetime(LifetimeParam {
-            attrs: Vec::new(),
-            lifetime: Lifetime {
-                apostrophe: Span::call_site(),
-                ident: Ident::new("de", Span::call_site()),
-            },
-            colon_token: None,
-            bounds: input
-                .generics
-                .lifetimes()
-                .map(|l| l.lifetime.clone())
-                .collect(),
-        }),
-    );
-    generics_cl.type_params_mut().for_each(|t| {
-        t.bounds
-            .push_value(TypeParamBound::Verbatim(quote!(serde::Deserialize<'de>)));
-    });
+                            let element: Option<__InternalSerdeIndexedDeserializeWith #ty_generics_with_de> = seq.next_element()?;
+                            element.map(|element| element.value)
+                        }
+                    )
+                }
+                None => quote_spanned!(span => seq.next_element()?),
+            };
 
-    let (impl_generics_with_de, ty_generics_with_de, _) = generics_cl.split_for_impl();
+            let missing = default_value(field, container_default).unwrap_or_else(
+                || quote!(return Err(serde::de::Error::invalid_length(#position, &self))),
+            );
 
-    let match_fields = match_fields(
-        &input.fields,
-        input.attrs.offset,
-        &impl_generics_with_de,
-        &ty_generics,
-        &ty_generics_with_de,
-        where_clause,
-        &ident,
-    );
+            let validate = validate_field(
+                field,
+                &format_ident!("element"),
+                format!("position `{position}`"),
+            );
 
-    let the_loop = if !input.fields.is_empty() {
-        // NB: In the previous "none_fields", we use the actual struct's
-        // keys as variable names. If the struct happens to have a key
-        // named "key", it would clash with __serde_indexed_internal_key,
-        // if that were named key.
-        quote! {
-            while let Some(__serde_indexed_internal_key) = map.next_key()? {
-                match __serde_indexed_internal_key {
-                    #(#match_fields)*
-                    _ => {
-                        // Ignore unknown keys by consuming their value
-                        let _ = map.next_value::<serde::de::IgnoredAny>()?;
+            quote_spanned! { span =>
+                let #ident = match #next_element {
+                    Some(element) => {
+                        #validate
+                        element
                     }
-                }
+                    None => #missing,
+                };
             }
-        }
-    } else {
-        quote! {}
-    };
+        });
+    let skipped = fields
+        .iter()
+        .filter(|field| field.skip_serializing_if.is_always())
+        .map(|field| {
+            let ident = field.binding();
+            let default = default_value(field, container_default)
+                .unwrap_or_else(|| quote!(::core::default::Default::default()));
+            quote! {
+                let #ident = #default;
+            }
+        });
+    elements.chain(skipped).collect()
+}
 
-    let res = quote! {
-        #[automatically_derived]
-        impl #impl_generics_with_de serde::Deserialize<'de> for #ident #ty_generics #where_clause {
-            fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+/// Describes the expected indices for the error message of unknown keys, like `serde::de::Error::unknown_field`.
+fn expected_indices(fields: &[parse::Field], keys: Keys) -> String {
+    let mut indexed_fields: Vec<&parse::Field> = fields
+        .iter()
+        .filter(|field| field.is_deserialized())
+        .collect();
+    // Integer indices are sorted numerically, followed by the text indices
+    indexed_fields.sort_by(|a, b| match (a.index, b.index) {
+        (Some(_), Some(_)) => keys.index(a).cmp(&keys.index(b)),
+        _ => keys.canonical_order(a, b),
+    });
+    let indices: Vec<String> = indexed_fields
+        .iter()
+        .map(|field| format!("`{}`", keys.display(field)))
+        .collect();
+    // The indices of a flattened field are only known by its type
+    match (
+        indices.len(),
+        fields
+            .iter()
+            .find(|field| field.flatten)
+            .map(|field| &field.label),
+    ) {
+        (0, None) => "there are no fields".to_owned(),
+        (1, None) => format!("expected {}", indices[0]),
+        (_, None) => format!("expected one of {}", indices.join(", ")),
+        (0, Some(label)) => format!("expected an index of the flattened field `{label}`"),
+        (_, Some(label)) => format!(
+            "expected {} or an index of the flattened field `{label}`",
+            indices.join(", ")
+        ),
+    }
+}
+
+/// Implements `Deserialize` for `__SerdeIndexedKey`, which is deserialized from an integer key or
+/// a text key handled by `visit_str`.
+///
+/// `index` converts an integer key of type `key_ty` into a `__SerdeIndexedKey`.
+fn deserialize_key(
+    key_ty: &proc_macro2::TokenStream,
+    expecting: &str,
+    index: proc_macro2::TokenStream,
+    visit_str: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    quote! {
+        impl<'de> serde::Deserialize<'de> for __SerdeIndexedKey {
+            fn deserialize<__D>(deserializer: __D) -> core::result::Result<Self, __D::Error>
             where
-                D: serde::Deserializer<'de>,
+                __D: serde::Deserializer<'de>,
             {
-                struct IndexedVisitor #impl_generics (core::marker::PhantomData<#ident #ty_generics>);
+                struct KeyVisitor;
 
-                impl #impl_generics_with_de serde::de::Visitor<'de> for IndexedVisitor #ty_generics {
-                    type Value = #ident #ty_generics;
+                impl<'de> serde::de::Visitor<'de> for KeyVisitor {
+                    type Value = __SerdeIndexedKey;
 
                     fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
-                        formatter.write_str(stringify!(#ident))
+                        formatter.write_str(#expecting)
                     }
 
-                    fn visit_map<V>(self, mut map: V) -> core::result::Result<Self::Value, V::Error>
+                    fn visit_u64<__E>(self, value: u64) -> core::result::Result<Self::Value, __E>
                     where
-                        V: serde::de::MapAccess<'de>,
+                        __E: serde::de::Error,
                     {
-                        #(#none_fields)*
+                        <#key_ty>::try_from(value)
+                            .map(#index)
+                            .map_err(|_| {
+                                serde::de::Error::invalid_value(serde::de::Unexpected::Unsigned(value), &self)
+                            })
+                    }
 
-                        #the_loop
+                    fn visit_i64<__E>(self, value: i64) -> core::result::Result<Self::Value, __E>
+                    where
+                        __E: serde::de::Error,
+                    {
+                        <#key_ty>::try_from(value)
+                            .map(#index)
+                            .map_err(|_| {
+                                serde::de::Error::invalid_value(serde::de::Unexpected::Signed(value), &self)
+                            })
+                    }
+
+                    fn visit_str<__E>(self, value: &str) -> core::result::Result<Self::Value, __E>
+                    where
+                        __E: serde::de::Error,
+                    {
+                        #visit_str
+                    }
+                }
+
+                deserializer.deserialize_any(KeyVisitor)
+            }
+        }
+    }
+}
+
+/// Defines `__SerdeIndexedKey`, which accepts both the index and the label of a field as key.
+///
+/// Unknown labels are deserialized as `None`, unless `deny_unknown_fields` is set.
+fn human_readable_key(
+    fields: &[parse::Field],
+    keys: Keys,
+    deny_unknown_fields: bool,
+) -> proc_macro2::TokenStream {
+    let key_ty = keys.ty();
+    let indexed_fields: Vec<_> = fields
+        .iter()
+        .filter(|field| field.is_deserialized())
+        .collect();
+    let labels = indexed_fields.iter().map(|field| &field.label);
+    let match_labels = indexed_fields.iter().map(|field| {
+        let label = &field.label;
+        let index = keys.literal(field);
+        quote! {
+            #label => Ok(__SerdeIndexedKey(Some(#index))),
+        }
+    });
+    let unknown_label = if deny_unknown_fields {
+        quote!(Err(serde::de::Error::unknown_field(value, &[#(#labels),*])))
+    } else {
+        quote!(Ok(__SerdeIndexedKey(None)))
+    };
+    let deserialize = deserialize_key(
+        &key_ty,
+        "an index or a field name",
+        quote!(|key| __SerdeIndexedKey(Some(key))),
+        quote! {
+            match value {
+                #(#match_labels)*
+                // Formats like JSON only support string keys
+                _ => match value.parse::<#key_ty>() {
+                    Ok(key) => Ok(__SerdeIndexedKey(Some(key))),
+                    Err(_) => #unknown_label,
+                },
+            }
+        },
+    );
+
+    quote! {
+        struct __SerdeIndexedKey(Option<#key_ty>);
+
+        #deserialize
+    }
+}
+
+/// Defines `__SerdeIndexedKey`, which holds an integer or a text index, for structs with text indices.
+///
+/// Known text indices are mapped to a static string so that the key does not borrow from the
+/// input.  Unknown text indices are only kept as `Unknown`, as they are ignored or rejected anyway.
+fn text_key(
+    fields: &[parse::Field],
+    keys: Keys,
+    deny_unknown_fields: bool,
+) -> proc_macro2::TokenStream {
+    let key_ty = keys.ty();
+    let text_indices: Vec<_> = fields
+        .iter()
+        .filter(|field| field.is_deserialized())
+        .filter_map(|field| field.text_index.as_ref())
+        .collect();
+    let unknown_text = if deny_unknown_fields {
+        let expected = expected_indices(fields, keys);
+        quote! {
+            Err(serde::de::Error::custom(format_args!(
+                "unknown index `{:?}`, {}",
+                value,
+                #expected,
+            )))
+        }
+    } else {
+        quote!(Ok(__SerdeIndexedKey::Unknown))
+    };
+    let deserialize = deserialize_key(
+        &key_ty,
+        "an integer or a text index",
+        quote!(__SerdeIndexedKey::Index),
+        quote! {
+            match value {
+                #(#text_indices => Ok(__SerdeIndexedKey::Text(#text_indices)),)*
+                _ => #unknown_text,
+            }
+        },
+    );
+
+    quote! {
+        // Variants may be unused if all fields with a text or integer index are skipped
+        #[allow(dead_code)]
+        enum __SerdeIndexedKey {
+            Index(#key_ty),
+            Text(&'static str),
+            Unknown,
+        }
+
+        impl core::fmt::Display for __SerdeIndexedKey {
+            fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                match self {
+                    Self::Index(index) => write!(formatter, "{}", index),
+                    Self::Text(text) => write!(formatter, "{:?}", text),
+                    Self::Unknown => formatter.write_str("unknown"),
+                }
+            }
+        }
+
+        #deserialize
+    }
+}
+
+fn all_fields(fields: &[parse::Field]) -> Vec<proc_macro2::TokenStream> {
+    fields
+        .iter()
+        .map(|field| {
+            let ident = field.binding();
+            let span = field.original_span;
+            match &field.member {
+                syn::Member::Named(_) => quote_spanned! { span =>
+                    #ident
+                },
+                syn::Member::Unnamed(member) => quote_spanned! { span =>
+                    #member: #ident
+                },
+            }
+        })
+        .collect()
+}
+
+/// Defines the hidden methods that are used to deserialize this struct as a flattened field.
+///
+/// The entries for the flattened struct are collected in a state tuple by the parent struct.  As
+/// the type of this tuple must be named in the signatures, structs that have flattened fields
+/// themselves cannot be flattened, and neither can structs with text indices.  The catch-all field
+/// for unknown entries stays empty.
+///
+/// For internally tagged enums, `__serde_indexed_unknown` rejects unknown keys if the struct has
+/// `deny_unknown_fields`, and `__SERDE_INDEXED_INDICES` lists all indices of the struct so that
+/// the enum can check that they sort after the tag.
+fn deserialize_flatten_methods(
+    input: &Input,
+    fields: &[parse::Field],
+    impl_generics_with_de: &ImplGenerics<'_>,
+    ty_generics: &TypeGenerics<'_>,
+    ty_generics_with_de: &TypeGenerics<'_>,
+    where_clause: Option<&WhereClause>,
+) -> proc_macro2::TokenStream {
+    if fields
+        .iter()
+        .any(|field| field.flatten || field.text_index.is_some())
+    {
+        return quote! {};
+    }
+    let ident = &input.ident;
+    let vis = &input.vis;
+    // The keys are passed by the parent struct as `i64`
+    let keys = Keys {
+        signed: true,
+        human_readable: false,
+        ..Keys::new(fields, &input.attrs)
+    };
+    let state_fields: Vec<_> = fields
+        .iter()
+        .filter(|field| field.is_deserialized())
+        .collect();
+    let bindings: Vec<_> = state_fields.iter().map(|field| field.binding()).collect();
+    let types: Vec<_> = state_fields.iter().map(|field| &field.ty).collect();
+    let state_ty = quote!((#(Option<#types>,)*));
+
+    let entry = if state_fields.is_empty() {
+        quote! {
+            #[doc(hidden)]
+            #vis fn __serde_indexed_entry<__V>(
+                _state: &mut (),
+                _key: i64,
+                _map: &mut __V,
+            ) -> core::result::Result<bool, __V::Error>
+            where
+                __V: serde::de::MapAccess<'de>,
+            {
+                Ok(false)
+            }
+        }
+    } else {
+        let match_fields = match_fields(
+            fields,
+            keys,
+            impl_generics_with_de,
+            ty_generics,
+            ty_generics_with_de,
+            where_clause,
+            ident,
+        );
+        quote! {
+            #[doc(hidden)]
+            #vis fn __serde_indexed_entry<__V>(
+                __serde_indexed_state: &mut #state_ty,
+                __serde_indexed_internal_key: i64,
+                map: &mut __V,
+            ) -> core::result::Result<bool, __V::Error>
+            where
+                __V: serde::de::MapAccess<'de>,
+            {
+                let (#(mut #bindings,)*) =
+                    core::mem::replace(__serde_indexed_state, Self::__serde_indexed_state());
+                match __serde_indexed_internal_key {
+                    #(#match_fields)*
+                    _ => {
+                        *__serde_indexed_state = (#(#bindings,)*);
+                        return Ok(false);
+                    }
+                }
+                *__serde_indexed_state = (#(#bindings,)*);
+                Ok(true)
+            }
+        }
+    };
+
+    let container_default = container_default(&input.attrs.default, quote!(Self));
+    let unknown_fields = fields.iter().filter(|field| field.unknown).map(|field| {
+        let ident = field.binding();
+        let ty = &field.ty;
+        quote! {
+            let #ident: #ty = ::core::default::Default::default();
+        }
+    });
+    let unwrap_expected_fields = unwrap_expected_fields(fields, &input.attrs.default);
+    let all_fields = all_fields(fields);
+    let validate = input.attrs.validate.as_ref().map(|path| {
+        quote! {
+            if let Err(error) = #path(&__serde_indexed_value) {
+                return Err(serde::de::Error::custom(error));
+            }
+        }
+    });
+    let unknown = if input.attrs.deny_unknown_fields {
+        let expected = expected_indices(fields, keys);
+        quote! {
+            Err(serde::de::Error::custom(format_args!(
+                "unknown index `{}`, {}",
+                __serde_indexed_internal_key,
+                #expected,
+            )))
+        }
+    } else {
+        quote!(Ok(()))
+    };
+    let indices = fields
+        .iter()
+        .filter(|field| field.index.is_some())
+        .flat_map(|field| {
+            core::iter::once(keys.index(field))
+                .chain(field.aliases.iter().map(|alias| alias + keys.offset))
+        })
+        .map(Literal::i64_suffixed);
+    let (impl_generics, _, plain_where_clause) = input.generics.split_for_impl();
+
+    quote! {
+        #[automatically_derived]
+        #[allow(dead_code)]
+        impl #impl_generics #ident #ty_generics #plain_where_clause {
+            #[doc(hidden)]
+            #vis const __SERDE_INDEXED_INDICES: &'static [i64] = &[#(#indices),*];
+        }
+
+        #[automatically_derived]
+        #[allow(dead_code, clippy::type_complexity, clippy::unused_unit)]
+        impl #impl_generics_with_de #ident #ty_generics #where_clause {
+            #[doc(hidden)]
+            #vis fn __serde_indexed_unknown<__E>(
+                __serde_indexed_internal_key: i64,
+            ) -> core::result::Result<(), __E>
+            where
+                __E: serde::de::Error,
+            {
+                #unknown
+            }
+
+            #[doc(hidden)]
+            #vis fn __serde_indexed_state() -> #state_ty {
+                (#(None::<#types>,)*)
+            }
+
+            #entry
+
+            #[doc(hidden)]
+            #vis fn __serde_indexed_finish<__E>(
+                (#(#bindings,)*): #state_ty,
+            ) -> core::result::Result<Self, __E>
+            where
+                __E: serde::de::Error,
+            {
+                #container_default
+                #(#unknown_fields)*
+                #(#unwrap_expected_fields)*
+                let __serde_indexed_value = #ident { #(#all_fields),* };
+                #validate
+                Ok(__serde_indexed_value)
+            }
+        }
+    }
+}
+
+/// Checks the result of the deserialization with the `validate` function of the container, if any.
+///
+/// This is used in `Deserialize::deserialize` instead of the visitor so that `Self` refers to the
+/// container in the path of the function.
+fn validated(
+    validate: Option<&syn::ExprPath>,
+    result: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    match validate {
+        None => result,
+        Some(path) => quote! {{
+            let __serde_indexed_value = #result?;
+            match #path(&__serde_indexed_value) {
+                Ok(()) => Ok(__serde_indexed_value),
+                Err(error) => Err(serde::de::Error::custom(error)),
+            }
+        }},
+    }
+}
+
+/// Adds the `'de` lifetime and `Deserialize<'de>` bounds to the generics of the input.
+fn generics_with_de<'a>(
+    input: &Input,
+    fields: impl IntoIterator<Item = &'a parse::Field> + Clone,
+) -> Generics {
+    let generics = &input.generics;
+    let mut generics_cl = generics.clone();
+    generics_cl.params.insert(
+        0,
+        syn::GenericParam::Lifetime(LifetimeParam {
+            attrs: Vec::new(),
+            lifetime: Lifetime {
+                apostrophe: Span::call_site(),
+                ident: Ident::new("de", Span::call_site()),
+            },
+            colon_token: None,
+            bounds: generics.lifetimes().map(|l| l.lifetime.clone()).collect(),
+        }),
+    );
+    add_bounds(
+        &mut generics_cl,
+        input.attrs.bound.deserialize.as_ref(),
+        fields.clone(),
+        |bounds| bounds.deserialize.as_ref(),
+        |field| {
+            (field.is_deserialized() || field.unknown || field.flatten)
+                && field.deserialize_with.is_none()
+        },
+        quote!(serde::Deserialize<'de>),
+    );
+    // Like serde_derive, also bound the type parameters of fields that take their value from
+    // `Default::default()`, e. g. skipped fields
+    if input.attrs.bound.deserialize.is_none() {
+        let predicates = inferred_bounds(
+            generics,
+            fields,
+            |bounds| bounds.deserialize.as_ref(),
+            |field| requires_default(field, &input.attrs.default),
+            quote!(::core::default::Default),
+        );
+        if !predicates.is_empty() {
+            generics_cl
+                .make_where_clause()
+                .predicates
+                .extend(predicates);
+        }
+    }
+    generics_cl
+}
+
+/// Returns true if the value of the field can be taken from `Default::default()` of its type.
+fn requires_default(field: &parse::Field, container_default: &DefaultValue) -> bool {
+    match field.default {
+        DefaultValue::Default => true,
+        DefaultValue::Path(_) => false,
+        DefaultValue::None => {
+            !field.is_deserialized()
+                && !field.unknown
+                && !field.flatten
+                && container_default.is_none()
+        }
+    }
+}
+
+#[proc_macro_derive(DeserializeIndexed, attributes(serde, serde_indexed))]
+pub fn derive_deserialize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as Input);
+    TokenStream::from(with_serde_path(
+        &input,
+        match &input.data {
+            _ if input.attrs.from.is_some() || input.attrs.try_from.is_some() => {
+                deserialize_from(&input)
+            }
+            Data::Struct(fields) => deserialize_struct(&input, fields),
+            Data::Newtype(field) => deserialize_newtype(&input, field),
+            Data::Enum(variants) => match input.attrs.tag {
+                Some(tag) => deserialize_tagged_enum(&input, tag, variants),
+                None => deserialize_enum(&input, variants),
+            },
+        },
+    ))
+}
+
+/// Deserializes the type given by the `from` or `try_from` attribute and converts it into the value.
+fn deserialize_from(input: &Input) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let generics_cl = generics_with_de(input, &[]);
+    let (impl_generics_with_de, _, where_clause) = generics_cl.split_for_impl();
+
+    let body = match (&input.attrs.from, &input.attrs.try_from) {
+        (Some(from), _) => quote! {
+            <#from as serde::Deserialize<'de>>::deserialize(deserializer).map(core::convert::From::from)
+        },
+        (None, Some(try_from)) => quote! {
+            <#try_from as serde::Deserialize<'de>>::deserialize(deserializer).and_then(|value| {
+                core::convert::TryFrom::try_from(value).map_err(serde::de::Error::custom)
+            })
+        },
+        (None, None) => unreachable!(),
+    };
+    let body = validated(input.attrs.validate.as_ref(), body);
+
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics_with_de serde::Deserialize<'de> for #ident #ty_generics #where_clause {
+            fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                #body
+            }
+        }
+    }
+}
+
+fn deserialize_newtype(input: &Input, field: &parse::Field) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let generics_cl = generics_with_de(input, core::slice::from_ref(field));
+    let (impl_generics_with_de, _, where_clause) = generics_cl.split_for_impl();
+
+    let body = match &field.deserialize_with {
+        None => quote!(serde::Deserialize::deserialize(deserializer).map(#ident)),
+        Some(f) => quote!(#f(deserializer).map(#ident)),
+    };
+    let body = validated(input.attrs.validate.as_ref(), body);
+
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics_with_de serde::Deserialize<'de> for #ident #ty_generics #where_clause {
+            fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                #body
+            }
+        }
+    }
+}
+
+fn deserialize_struct(input: &Input, fields: &[parse::Field]) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let all_fields = all_fields(fields);
+    let container_default = container_default(&input.attrs.default, quote!(Self::Value));
+
+    let (impl_generics, ty_generics, _) = input.generics.split_for_impl();
+
+    let generics_cl = generics_with_de(input, fields);
+    let (impl_generics_with_de, ty_generics_with_de, where_clause) = generics_cl.split_for_impl();
+
+    let mut flatten_methods = quote! {};
+    let (visit, deserialize) = if input.attrs.array {
+        let next_elements = next_elements(
+            fields,
+            &input.attrs.default,
+            &impl_generics_with_de,
+            &ty_generics,
+            &ty_generics_with_de,
+            where_clause,
+            ident,
+        );
+        let len = array_fields(fields).len();
+        let trailing_elements = if input.attrs.deny_unknown_fields {
+            quote! {
+                if seq.next_element::<serde::de::IgnoredAny>()?.is_some() {
+                    return Err(serde::de::Error::invalid_length(#len + 1, &self));
+                }
+            }
+        } else {
+            quote! {
+                // Ignore trailing elements by consuming them
+                while seq.next_element::<serde::de::IgnoredAny>()?.is_some() {}
+            }
+        };
+        let visit = quote! {
+            fn visit_seq<V>(self, mut seq: V) -> core::result::Result<Self::Value, V::Error>
+            where
+                V: serde::de::SeqAccess<'de>,
+            {
+                #container_default
+
+                #(#next_elements)*
+
+                #trailing_elements
+
+                Ok(#ident { #(#all_fields),* })
+            }
+        };
+        let deserialize =
+            quote!(deserializer.deserialize_tuple(#len, IndexedVisitor(Default::default())));
+        (visit, deserialize)
+    } else {
+        let none_fields = none_fields(fields);
+        let unwrap_expected_fields = unwrap_expected_fields(fields, &input.attrs.default);
+
+        let keys = Keys::new(fields, &input.attrs);
+        let key_ty = keys.ty();
+
+        let match_fields = match_fields(
+            fields,
+            keys,
+            &impl_generics_with_de,
+            &ty_generics,
+            &ty_generics_with_de,
+            where_clause,
+            ident,
+        );
+
+        let flattened = fields.iter().filter(|field| field.flatten).map(|field| {
+            let ident = field.binding();
+            let ty = &field.ty;
+            quote! {
+                if <#ty>::__serde_indexed_entry(&mut #ident, __serde_indexed_internal_key, &mut map)? {
+                    continue;
+                }
+            }
+        });
+        let unknown_key = if let Some(field) = fields.iter().find(|field| field.unknown) {
+            insert_unknown(field)
+        } else if input.attrs.deny_unknown_fields {
+            let expected = expected_indices(fields, keys);
+            quote! {
+                return Err(serde::de::Error::custom(format_args!(
+                    "unknown index `{}`, {}",
+                    __serde_indexed_internal_key,
+                    #expected,
+                )));
+            }
+        } else {
+            quote! {
+                // Ignore unknown keys by consuming their value
+                let _ = map.next_value::<serde::de::IgnoredAny>()?;
+            }
+        };
+
+        let (previous_key, check_order) = if input.attrs.canonical {
+            keys.check_canonical_order()
+        } else {
+            (quote! {}, quote! {})
+        };
+
+        let match_key = quote! {
+            #check_order
+            match __serde_indexed_internal_key {
+                #(#match_fields)*
+                _ => {
+                    #(#flattened)*
+                    #unknown_key
+                }
+            }
+        };
+
+        // NB: In the previous "none_fields", we use the actual struct's
+        // keys as variable names. If the struct happens to have a key
+        // named "key", it would clash with __serde_indexed_internal_key,
+        // if that were named key.
+        let the_loop = if keys.human_readable {
+            let human_readable_key =
+                human_readable_key(fields, keys, input.attrs.deny_unknown_fields);
+            quote! {
+                #human_readable_key
+                #previous_key
+                while let Some(__SerdeIndexedKey(__serde_indexed_internal_key)) = map.next_key()? {
+                    let Some(__serde_indexed_internal_key) = __serde_indexed_internal_key else {
+                        // Ignore unknown labels by consuming their value
+                        let _ = map.next_value::<serde::de::IgnoredAny>()?;
+                        continue;
+                    };
+                    #match_key
+                }
+            }
+        } else if keys.text {
+            let text_key = text_key(fields, keys, input.attrs.deny_unknown_fields);
+            quote! {
+                #text_key
+                while let Some(__serde_indexed_internal_key) = map.next_key::<__SerdeIndexedKey>()? {
+                    #match_key
+                }
+            }
+        } else {
+            quote! {
+                #previous_key
+                while let Some(__serde_indexed_internal_key) = map.next_key::<#key_ty>()? {
+                    #match_key
+                }
+            }
+        };
+
+        let visit = quote! {
+            fn visit_map<V>(self, mut map: V) -> core::result::Result<Self::Value, V::Error>
+            where
+                V: serde::de::MapAccess<'de>,
+            {
+                #container_default
+
+                #(#none_fields)*
+
+                #the_loop
+
+                #(#unwrap_expected_fields)*
+
+                Ok(#ident { #(#all_fields),* })
+            }
+        };
+        let deserialize = quote!(deserializer.deserialize_map(IndexedVisitor(Default::default())));
+        flatten_methods = deserialize_flatten_methods(
+            input,
+            fields,
+            &impl_generics_with_de,
+            &ty_generics,
+            &ty_generics_with_de,
+            where_clause,
+        );
+        (visit, deserialize)
+    };
+    let validated = validated(input.attrs.validate.as_ref(), deserialize);
+
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics_with_de serde::Deserialize<'de> for #ident #ty_generics #where_clause {
+            fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                struct IndexedVisitor #impl_generics (core::marker::PhantomData<#ident #ty_generics>);
+
+                impl #impl_generics_with_de serde::de::Visitor<'de> for IndexedVisitor #ty_generics #where_clause {
+                    type Value = #ident #ty_generics;
+
+                    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        formatter.write_str(stringify!(#ident))
+                    }
+
+                    #visit
+                }
+
+                #validated
+            }
+        }
+
+        #flatten_methods
+    }
+}
+
+fn match_variants<'a>(
+    ident: &Ident,
+    variants: impl Iterator<Item = &'a parse::Variant>,
+) -> Vec<proc_macro2::TokenStream> {
+    variants
+        .map(|variant| {
+            let variant_ident = &variant.ident;
+            let index = Literal::i64_unsuffixed(variant.index);
+            let span = variant.original_span;
+            quote_spanned! { span =>
+                #index => Ok(#ident::#variant_ident),
+            }
+        })
+        .collect()
+}
+
+/// Reads the tag from the first entry and deserializes the other entries into the field of the
+/// variant, using the hidden methods that are also used for flattened fields.
+///
+/// Unless `tag_first` is set, the indices of the fields are checked at compile time to sort after
+/// the tag, so that the tag is the first entry in canonical order.
+fn deserialize_tagged_enum(
+    input: &Input,
+    tag: i64,
+    variants: &[parse::Variant],
+) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, plain_where_clause) = input.generics.split_for_impl();
+
+    let fields: Vec<_> = variants
+        .iter()
+        .filter_map(|variant| Some((&variant.ident, variant.field.as_ref()?)))
+        .collect();
+    let (tag_order, check_tag_order) = if input.attrs.tag_first || fields.is_empty() {
+        (quote! {}, quote! {})
+    } else {
+        let tag_literal = Literal::i64_suffixed(tag);
+        // Unsigned keys sort before negative keys, see `canonical_rank`
+        let sorts_after_tag = if tag < 0 {
+            quote!(index < #tag_literal)
+        } else {
+            quote!(index < 0 || index > #tag_literal)
+        };
+        let checks = fields.iter().map(|(variant_ident, field)| {
+            let ty = &field.ty;
+            let message = Literal::string(&format!(
+                "the field of the variant `{variant_ident}` has an index that sorts before the tag `{tag}`, use `tag_first` to always serialize the tag first"
+            ));
+            quote! {
+                let indices = <#ty>::__SERDE_INDEXED_INDICES;
+                let mut i = 0;
+                while i < indices.len() {
+                    let index = indices[i];
+                    assert!(#sorts_after_tag, #message);
+                    i += 1;
+                }
+            }
+        });
+        (
+            quote! {
+                #[automatically_derived]
+                impl #impl_generics #ident #ty_generics #plain_where_clause {
+                    #[doc(hidden)]
+                    const __SERDE_INDEXED_TAG_ORDER: () = {
+                        #({ #checks })*
+                    };
+                }
+            },
+            quote! {
+                #[allow(clippy::let_unit_value)]
+                let () = Self::__SERDE_INDEXED_TAG_ORDER;
+            },
+        )
+    };
+
+    let generics_cl = generics_with_de(
+        input,
+        variants.iter().filter_map(|variant| variant.field.as_ref()),
+    );
+    let (impl_generics_with_de, _, where_clause) = generics_cl.split_for_impl();
+
+    let tag_key = Literal::i64_suffixed(tag);
+    let match_variants = variants.iter().map(|variant| {
+        let variant_ident = &variant.ident;
+        let tag_value = Literal::i64_unsuffixed(variant.index);
+        let span = variant.original_span;
+        if let Some(field) = &variant.field {
+            let ty = &field.ty;
+            quote_spanned! { span =>
+                #tag_value => {
+                    let mut state = <#ty>::__serde_indexed_state();
+                    while let Some(__serde_indexed_internal_key) = map.next_key::<i64>()? {
+                        if !<#ty>::__serde_indexed_entry(&mut state, __serde_indexed_internal_key, &mut map)? {
+                            <#ty>::__serde_indexed_unknown(__serde_indexed_internal_key)?;
+                            // Ignore unknown keys by consuming their value
+                            let _ = map.next_value::<serde::de::IgnoredAny>()?;
+                        }
+                    }
+                    Ok(#ident::#variant_ident(<#ty>::__serde_indexed_finish(state)?))
+                }
+            }
+        } else {
+            quote_spanned! { span =>
+                #tag_value => {
+                    // Ignore all other entries by consuming them
+                    while map
+                        .next_entry::<serde::de::IgnoredAny, serde::de::IgnoredAny>()?
+                        .is_some()
+                    {}
+                    Ok(#ident::#variant_ident)
+                }
+            }
+        }
+    });
+
+    quote! {
+        #tag_order
+
+        #[automatically_derived]
+        impl #impl_generics_with_de serde::Deserialize<'de> for #ident #ty_generics #where_clause {
+            fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                #check_tag_order
 
-                        #(#unwrap_expected_fields)*
+                struct IndexedVisitor #impl_generics (core::marker::PhantomData<#ident #ty_generics>);
+
+                impl #impl_generics_with_de serde::de::Visitor<'de> for IndexedVisitor #ty_generics #where_clause {
+                    type Value = #ident #ty_generics;
+
+                    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        formatter.write_str(stringify!(#ident))
+                    }
 
-                        Ok(#ident { #(#all_fields),* })
+                    fn visit_map<V>(self, mut map: V) -> core::result::Result<Self::Value, V::Error>
+                    where
+                        V: serde::de::MapAccess<'de>,
+                    {
+                        match map.next_key::<i64>()? {
+                            Some(#tag_key) => {}
+                            Some(__serde_indexed_internal_key) => {
+                                return Err(serde::de::Error::custom(format_args!(
+                                    "expected the tag `{}` as first key, found `{}`",
+                                    #tag_key,
+                                    __serde_indexed_internal_key,
+                                )));
+                            }
+                            None => {
+                                return Err(serde::de::Error::custom(format_args!(
+                                    "missing tag `{}`",
+                                    #tag_key,
+                                )));
+                            }
+                        }
+                        let __serde_indexed_tag = map.next_value::<i64>()?;
+                        match __serde_indexed_tag {
+                            #(#match_variants)*
+                            _ => Err(serde::de::Error::invalid_value(
+                                serde::de::Unexpected::Signed(__serde_indexed_tag),
+                                &"a tag value of the enum",
+                            )),
+                        }
                     }
                 }
 
                 deserializer.deserialize_map(IndexedVisitor(Default::default()))
             }
         }
+    }
+}
+
+fn deserialize_enum(input: &Input, variants: &[parse::Variant]) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, _) = input.generics.split_for_impl();
+
+    let generics_cl = generics_with_de(
+        input,
+        variants.iter().filter_map(|variant| variant.field.as_ref()),
+    );
+    let (impl_generics_with_de, _, where_clause) = generics_cl.split_for_impl();
+
+    let unit_variants = || {
+        variants
+            .iter()
+            .filter(|variant| variant.field.is_none() && !variant.other)
+    };
+    let match_unsigned = match_variants(ident, unit_variants().filter(|v| v.index >= 0));
+    let match_signed = match_variants(ident, unit_variants());
+
+    let (unknown_unsigned, unknown_signed) = match variants.iter().find(|variant| variant.other) {
+        Some(variant) => {
+            let variant_ident = &variant.ident;
+            (
+                quote! {
+                    i64::try_from(value).map(#ident::#variant_ident).map_err(|_| {
+                        serde::de::Error::invalid_value(serde::de::Unexpected::Unsigned(value), &self)
+                    })
+                },
+                quote!(Ok(#ident::#variant_ident(value))),
+            )
+        }
+        None => (
+            quote! {
+                Err(serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Unsigned(value),
+                    &self,
+                ))
+            },
+            quote! {
+                Err(serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Signed(value),
+                    &self,
+                ))
+            },
+        ),
+    };
+
+    let has_data = variants.iter().any(|variant| variant.field.is_some());
+    let has_labels = variants.iter().any(|variant| variant.label.is_some());
+
+    let visit_map = if has_data {
+        let match_keys = variants.iter().filter_map(|variant| {
+            let variant_ident = &variant.ident;
+            let index = Literal::i64_unsuffixed(variant.index);
+            let ty = &variant.field.as_ref()?.ty;
+            let span = variant.original_span;
+            Some(quote_spanned! { span =>
+                #index => #ident::#variant_ident(map.next_value::<#ty>()?),
+            })
+        });
+        quote! {
+                fn visit_map<V>(self, mut map: V) -> core::result::Result<Self::Value, V::Error>
+                where
+                    V: serde::de::MapAccess<'de>,
+                {
+                    let Some(__serde_indexed_internal_key) = map.next_key::<i64>()? else {
+                        return Err(serde::de::Error::invalid_length(0, &self));
+                    };
+                    let value = match __serde_indexed_internal_key {
+                        #(#match_keys)*
+                        _ => {
+                            return Err(serde::de::Error::invalid_value(
+                                serde::de::Unexpected::Signed(__serde_indexed_internal_key),
+                                &self,
+                            ));
+                        }
+                    };
+                    if map.next_key::<serde::de::IgnoredAny>()?.is_some() {
+                        return Err(serde::de::Error::invalid_length(2, &self));
+                    }
+                    Ok(value)
+                }
+        }
+    } else {
+        quote! {}
+    };
+
+    let visit_str = if has_labels {
+        let match_labels = variants.iter().filter_map(|variant| {
+            let variant_ident = &variant.ident;
+            let label = variant.label.as_ref()?;
+            let span = variant.original_span;
+            Some(quote_spanned! { span =>
+                #label => Ok(#ident::#variant_ident),
+            })
+        });
+        quote! {
+            fn visit_str<__E>(self, value: &str) -> core::result::Result<Self::Value, __E>
+            where
+                __E: serde::de::Error,
+            {
+                match value {
+                    #(#match_labels)*
+                    _ => Err(serde::de::Error::invalid_value(
+                        serde::de::Unexpected::Str(value),
+                        &self,
+                    )),
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let expected = match (has_labels, has_data) {
+        (false, false) => "an index",
+        (true, false) => "an index or a label",
+        (false, true) => "an index or a map with a single entry",
+        (true, true) => "an index, a label or a map with a single entry",
+    };
+    let expecting = format!("{expected} of {ident}");
+
+    // Unit variants are serialized as integers and the other variants as maps, so the type of
+    // the value is only known for self-describing formats
+    let deserialize_index = if has_data || has_labels {
+        quote!(deserialize_any)
+    } else if variants
+        .iter()
+        .any(|variant| variant.other || variant.index < 0)
+    {
+        quote!(deserialize_i64)
+    } else {
+        quote!(deserialize_u64)
     };
-    TokenStream::from(res)
+
+    quote! {
+        #[automatically_derived]
+        impl #impl_generics_with_de serde::Deserialize<'de> for #ident #ty_generics #where_clause {
+            fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                struct IndexedVisitor #impl_generics (core::marker::PhantomData<#ident #ty_generics>);
+
+                impl #impl_generics_with_de serde::de::Visitor<'de> for IndexedVisitor #ty_generics #where_clause {
+                    type Value = #ident #ty_generics;
+
+                    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        formatter.write_str(#expecting)
+                    }
+
+                    fn visit_u64<__E>(self, value: u64) -> core::result::Result<Self::Value, __E>
+                    where
+                        __E: serde::de::Error,
+                    {
+                        match value {
+                            #(#match_unsigned)*
+                            _ => #unknown_unsigned,
+                        }
+                    }
+
+                    fn visit_i64<__E>(self, value: i64) -> core::result::Result<Self::Value, __E>
+                    where
+                        __E: serde::de::Error,
+                    {
+                        match value {
+                            #(#match_signed)*
+                            _ => #unknown_signed,
+                        }
+                    }
+
+                    #visit_str
+
+                    #visit_map
+                }
+
+                deserializer.#deserialize_index(IndexedVisitor(Default::default()))
+            }
+        }
+    }
 }
@@ -1,20 +1,50 @@
 use proc_macro2::Span;
+use syn::ext::IdentExt;
 use syn::meta::ParseNestedMeta;
 use syn::parse::{Error, Parse, ParseStream, Result};
+use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
-use syn::{Data, DeriveInput, Fields, Generics, Ident, LitInt, LitStr, Token};
+use syn::{DeriveInput, Fields, Generics, Ident, LitInt, LitStr, Token, WherePredicate};
 
 pub struct Input {
     pub ident: Ident,
+    pub vis: syn::Visibility,
     pub attrs: StructAttrs,
-    pub fields: Vec<Field>,
+    pub data: Data,
     pub generics: Generics,
 }
 
+pub enum Data {
+    Struct(Vec<Field>),
+    /// A tuple struct with a single field that is (de)serialized transparently.
+    Newtype(Box<Field>),
+    Enum(Vec<Variant>),
+}
+
 #[derive(Default)]
 pub struct StructAttrs {
     pub auto_index: bool,
-    pub offset: usize,
+    pub offset: i64,
+    pub array: bool,
+    pub default: DefaultValue,
+    pub deny_unknown_fields: bool,
+    pub canonical: bool,
+    pub human_readable: bool,
+    pub bound: Bounds,
+    /// The function that checks the value after deserialization.
+    pub validate: Option<syn::ExprPath>,
+    /// The type that the value is converted from after deserialization, see `from`.
+    pub from: Option<syn::Type>,
+    /// The type that the value is converted from with `TryFrom`, see `try_from`.
+    pub try_from: Option<syn::Type>,
+    /// The type that the value is converted into before serialization, see `into`.
+    pub into: Option<syn::Type>,
+    /// The path of the serde crate used by the generated code, if it is not `serde`.
+    pub serde_path: Option<syn::Path>,
+    /// The index of the entry that holds the variant of an internally tagged enum.
+    pub tag: Option<i64>,
+    /// Always serialize the tag entry first, even if it is not in canonical order, see `tag_first`.
+    pub tag_first: bool,
     // pub skip_nones: bool,
 }
 
@@ -33,30 +63,210 @@ impl Skip {
     }
 }
 
+/// Custom where predicates set with the `bound` attribute, replacing the inferred bounds.
+#[derive(Default)]
+pub struct Bounds {
+    pub serialize: Option<Vec<WherePredicate>>,
+    pub deserialize: Option<Vec<WherePredicate>>,
+}
+
+/// The value used for a field that is missing during deserialization.
+#[derive(Default)]
+pub enum DefaultValue {
+    #[default]
+    None,
+    Default,
+    Path(syn::ExprPath),
+}
+
+impl Field {
+    /// Returns true if the field is written during serialization, unless `skip_serializing_if` applies.
+    pub fn is_serialized(&self) -> bool {
+        self.has_index() && !self.skip_serializing
+    }
+
+    /// Returns true if the field is read during deserialization.
+    pub fn is_deserialized(&self) -> bool {
+        self.has_index() && !self.skip_deserializing
+    }
+
+    /// Returns true if the field has an integer or text index, i. e. if it is not skipped.
+    pub fn has_index(&self) -> bool {
+        self.index.is_some() || self.text_index.is_some()
+    }
+
+    /// The identifier of the local variable holding the value of the field during deserialization.
+    pub fn binding(&self) -> Ident {
+        match &self.member {
+            syn::Member::Named(ident) => ident.clone(),
+            syn::Member::Unnamed(index) => {
+                Ident::new(&format!("__field{}", index.index), index.span)
+            }
+        }
+    }
+}
+
+impl DefaultValue {
+    pub fn is_none(&self) -> bool {
+        matches!(self, Self::None)
+    }
+}
+
 pub struct Field {
     pub label: String,
     pub member: syn::Member,
-    pub index: Option<usize>,
+    pub index: Option<i64>,
+    /// The text string that is used as key instead of an integer index.
+    pub text_index: Option<String>,
+    /// Additional indices that are accepted during deserialization.
+    pub aliases: Vec<i64>,
     pub skip_serializing_if: Skip,
+    pub skip_serializing: bool,
+    pub skip_deserializing: bool,
     pub serialize_with: Option<syn::ExprPath>,
     pub deserialize_with: Option<syn::ExprPath>,
+    pub default: DefaultValue,
+    pub bound: Bounds,
+    /// The function that checks the value of the field after deserialization.
+    pub validate: Option<syn::ExprPath>,
     pub no_increment: bool,
+    pub unknown: bool,
+    /// The field is a struct whose entries are merged into the map of this struct.
+    pub flatten: bool,
     pub ty: syn::Type,
     pub original_span: Span,
 }
 
+pub struct Variant {
+    pub ident: Ident,
+    /// The index of the variant.  Unused for the `other` variant.
+    pub index: i64,
+    /// The field of a newtype variant.
+    pub field: Option<Field>,
+    /// The variant holds all unknown indices as its `i64` field.
+    pub other: bool,
+    /// A text label that is accepted instead of the index during deserialization.
+    pub label: Option<LitStr>,
+    pub original_span: Span,
+}
+
+/// Parses `default` or `default = "path"`.
+fn parse_default(meta: &ParseNestedMeta) -> Result<DefaultValue> {
+    if meta.input.peek(Token![=]) {
+        let litstr: LitStr = meta.value()?.parse()?;
+        Ok(DefaultValue::Path(litstr.parse()?))
+    } else {
+        Ok(DefaultValue::Default)
+    }
+}
+
+/// Parses `bound = "..."` or `bound(serialize = "...", deserialize = "...")`.
+fn parse_bound(bounds: &mut Bounds, meta: &ParseNestedMeta) -> Result<()> {
+    fn parse_predicates(
+        predicates: &mut Option<Vec<WherePredicate>>,
+        meta: &ParseNestedMeta,
+    ) -> Result<()> {
+        if predicates.is_some() {
+            return Err(meta.error("Multiple attributes for bound"));
+        }
+        let litstr: LitStr = meta.value()?.parse()?;
+        let parsed =
+            litstr.parse_with(Punctuated::<WherePredicate, Token![,]>::parse_terminated)?;
+        *predicates = Some(parsed.into_iter().collect());
+        Ok(())
+    }
+
+    if meta.input.peek(Token![=]) {
+        parse_predicates(&mut bounds.serialize, meta)?;
+        if bounds.deserialize.is_some() {
+            return Err(meta.error("Multiple attributes for bound"));
+        }
+        bounds.deserialize.clone_from(&bounds.serialize);
+        Ok(())
+    } else {
+        meta.parse_nested_meta(|bound_meta| {
+            if bound_meta.path.is_ident("serialize") {
+                parse_predicates(&mut bounds.serialize, &bound_meta)
+            } else if bound_meta.path.is_ident("deserialize") {
+                parse_predicates(&mut bounds.deserialize, &bound_meta)
+            } else {
+                Err(bound_meta.error("`bound` only accepts `serialize` and `deserialize` as keys"))
+            }
+        })
+    }
+}
+
 fn parse_meta(attrs: &mut StructAttrs, meta: ParseNestedMeta) -> Result<()> {
     if meta.path.is_ident("auto_index") {
         attrs.auto_index = true;
         Ok(())
     } else if meta.path.is_ident("offset") {
-        let value = meta.value()?;
-        let offset: LitInt = value.parse()?;
-        attrs.offset = offset.base10_parse()?;
+        attrs.offset = parse_signed_index(meta.value()?)?;
+        Ok(())
+    } else if meta.path.is_ident("array") {
+        attrs.array = true;
+        Ok(())
+    } else if meta.path.is_ident("default") {
+        if !attrs.default.is_none() {
+            return Err(meta.error("Multiple attributes for default"));
+        }
+        attrs.default = parse_default(&meta)?;
+        Ok(())
+    } else if meta.path.is_ident("deny_unknown_fields") {
+        attrs.deny_unknown_fields = true;
+        Ok(())
+    } else if meta.path.is_ident("canonical") {
+        attrs.canonical = true;
+        Ok(())
+    } else if meta.path.is_ident("human_readable") {
+        attrs.human_readable = true;
+        Ok(())
+    } else if meta.path.is_ident("bound") {
+        parse_bound(&mut attrs.bound, &meta)
+    } else if meta.path.is_ident("validate") {
+        if attrs.validate.is_some() {
+            return Err(meta.error("Multiple attributes for validate"));
+        }
+        let litstr: LitStr = meta.value()?.parse()?;
+        attrs.validate = Some(litstr.parse()?);
+        Ok(())
+    } else if meta.path.is_ident("from") || meta.path.is_ident("try_from") {
+        if attrs.from.is_some() || attrs.try_from.is_some() {
+            return Err(meta.error("Multiple attributes for from or try_from"));
+        }
+        let litstr: LitStr = meta.value()?.parse()?;
+        if meta.path.is_ident("from") {
+            attrs.from = Some(litstr.parse()?);
+        } else {
+            attrs.try_from = Some(litstr.parse()?);
+        }
+        Ok(())
+    } else if meta.path.is_ident("into") {
+        if attrs.into.is_some() {
+            return Err(meta.error("Multiple attributes for into"));
+        }
+        let litstr: LitStr = meta.value()?.parse()?;
+        attrs.into = Some(litstr.parse()?);
+        Ok(())
+    } else if meta.path.is_ident("crate") {
+        if attrs.serde_path.is_some() {
+            return Err(meta.error("Multiple attributes for crate"));
+        }
+        let litstr: LitStr = meta.value()?.parse()?;
+        attrs.serde_path = Some(litstr.parse()?);
+        Ok(())
+    } else if meta.path.is_ident("tag") {
+        if attrs.tag.is_some() {
+            return Err(meta.error("Multiple attributes for tag"));
+        }
+        attrs.tag = Some(parse_signed_index(meta.value()?)?);
+        Ok(())
+    } else if meta.path.is_ident("tag_first") {
+        attrs.tag_first = true;
         Ok(())
     } else {
         Err(meta.error(format_args!(
-            "the only accepted struct level attribute is offset"
+            "the only accepted struct level attributes are auto_index, offset, array, default, deny_unknown_fields, canonical, human_readable, bound, validate, from, try_from, into, crate, tag and tag_first"
         )))
     }
 }
@@ -84,30 +294,91 @@ impl Parse for Input {
         let call_site = Span::call_site();
         let derive_input = DeriveInput::parse(input)?;
 
-        let data: syn::DataStruct = match derive_input.data {
-            Data::Struct(data) => data,
-            _ => {
-                return Err(Error::new(call_site, "input must be a struct"));
-            }
-        };
-
         let attrs: StructAttrs = parse_attrs(&derive_input.attrs)?;
 
-        let syn_fields: syn::FieldsNamed = match data.fields {
-            Fields::Named(named_fields) => named_fields,
-            _ => {
-                return Err(Error::new(call_site, "struct fields must be named"));
-            }
-        };
+        // If the value is converted in both directions, its fields are never (de)serialized and
+        // don't need indices
+        if attrs.into.is_some() && (attrs.from.is_some() || attrs.try_from.is_some()) {
+            return Ok(Input {
+                ident: derive_input.ident,
+                vis: derive_input.vis,
+                attrs,
+                data: Data::Struct(Vec::new()),
+                generics: derive_input.generics,
+            });
+        }
 
-        let fields = fields_from_ast(&attrs, &syn_fields.named)?;
+        if attrs.tag_first && attrs.tag.is_none() {
+            return Err(Error::new(
+                call_site,
+                "tag_first requires the tag attribute",
+            ));
+        }
+
+        let data = match derive_input.data {
+            syn::Data::Struct(_) if attrs.tag.is_some() => {
+                return Err(Error::new(call_site, "tag is only supported for enums"));
+            }
+            syn::Data::Struct(data) => {
+                let syn_fields = match data.fields {
+                    Fields::Named(named_fields) => named_fields.named,
+                    Fields::Unnamed(unnamed_fields) if unnamed_fields.unnamed.len() == 1 => {
+                        check_no_struct_attrs(&attrs, "newtype structs")?;
+                        let field =
+                            parse_field(&attrs, 0, 0, &unnamed_fields.unnamed[0], &mut Vec::new())?;
+                        check_newtype_field(&field)?;
+                        return Ok(Input {
+                            ident: derive_input.ident,
+                            vis: derive_input.vis,
+                            attrs,
+                            data: Data::Newtype(Box::new(field)),
+                            generics: derive_input.generics,
+                        });
+                    }
+                    Fields::Unnamed(unnamed_fields) => {
+                        if attrs.human_readable {
+                            return Err(Error::new(
+                                call_site,
+                                "human_readable is not supported for tuple structs",
+                            ));
+                        }
+                        unnamed_fields.unnamed
+                    }
+                    Fields::Unit => {
+                        return Err(Error::new(call_site, "unit structs are not supported"));
+                    }
+                };
 
-        //serde::internals::ast calls `fields_from_ast(cx, &fields.named, attrs, container_default)`
+                //serde::internals::ast calls `fields_from_ast(cx, &fields.named, attrs, container_default)`
+                let fields = fields_from_ast(&attrs, &syn_fields)?;
+                if attrs.array {
+                    if attrs.canonical || attrs.human_readable {
+                        return Err(Error::new(
+                            call_site,
+                            "canonical and human_readable cannot be used in array mode",
+                        ));
+                    }
+                    check_array_fields(&fields)?;
+                }
+                Data::Struct(fields)
+            }
+            syn::Data::Enum(data) => {
+                check_no_struct_attrs(&attrs, "enums")?;
+                if attrs.validate.is_some() {
+                    return Err(Error::new(call_site, "validate is not supported for enums"));
+                }
+                Data::Enum(variants_from_ast(&data.variants, attrs.tag.is_some())?)
+            }
+            syn::Data::Union(_) => {
+                return Err(Error::new(call_site, "input must be a struct or an enum"));
+            }
+        };
 
         Ok(Input {
             ident: derive_input.ident,
+            vis: derive_input.vis,
             attrs,
-            fields,
+            data,
             generics: derive_input.generics,
         })
     }
@@ -115,23 +386,28 @@ impl Parse for Input {
 
 fn parse_field(
     attrs: &StructAttrs,
-    auto_index: usize,
+    auto_index: i64,
+    position: usize,
     field: &syn::Field,
-    indices: &mut Vec<usize>,
+    indices: &mut Vec<i64>,
 ) -> Result<Field> {
-    let ident = field
-        .ident
-        .as_ref()
-        .ok_or_else(|| Error::new_spanned(field, "Tuple structs are not supported"))?;
-
     let mut skip_serializing_if = Skip::Never;
+    let mut skip_serializing = false;
+    let mut skip_deserializing = false;
     let mut deserialize_with = None;
+    let mut default = DefaultValue::None;
+    let mut bound = Bounds::default();
+    let mut validate = None;
     let mut serialize_with = None;
     let mut no_increment = false;
     let mut explicit_index = None;
+    let mut text_index = None;
+    let mut aliases = Vec::new();
+    let mut unknown = false;
+    let mut flatten = false;
 
     for attr in &field.attrs {
-        if attr.path().is_ident("serde") {
+        if attr.path().is_ident("serde") || attr.path().is_ident("serde_indexed") {
             attr.parse_nested_meta(|meta| {
                 let parse_value = |attribute: &mut Option<_>, attribute_name: &str| {
                     let litstr: LitStr = meta.value()?.parse()?;
@@ -172,6 +448,22 @@ fn parse_field(
                     }
                     skip_serializing_if = Skip::Always;
                     Ok(())
+                } else if meta.path.is_ident("skip_serializing") {
+                    skip_serializing = true;
+                    Ok(())
+                } else if meta.path.is_ident("skip_deserializing") {
+                    skip_deserializing = true;
+                    Ok(())
+                } else if meta.path.is_ident("default") {
+                    if !default.is_none() {
+                        return Err(meta.error("Multiple attributes for default"));
+                    }
+                    default = parse_default(&meta)?;
+                    Ok(())
+                } else if meta.path.is_ident("bound") {
+                    parse_bound(&mut bound, &meta)
+                } else if meta.path.is_ident("validate") {
+                    parse_value(&mut validate, "validate")
                 } else if meta.path.is_ident("deserialize_with") {
                     parse_value(&mut deserialize_with, "deserialize_with")
                 } else if meta.path.is_ident("serialize_with") {
@@ -199,7 +491,7 @@ fn parse_field(
 
                     Ok(())
                 } else if meta.path.is_ident("index") {
-                    if explicit_index.is_some() {
+                    if explicit_index.is_some() || text_index.is_some() {
                         return Err(meta.error("Multiple attributes for index"));
                     }
                     if attrs.auto_index {
@@ -207,49 +499,170 @@ fn parse_field(
                             "The index attribute cannot be combined with the auto_index attribute",
                         ));
                     }
-                    let litint: LitInt = meta.value()?.parse()?;
-                    let int = litint.base10_parse()?;
-                    if indices.contains(&int) {
+                    if field.ident.is_none() {
+                        return Err(
+                            meta.error("The fields of tuple structs are indexed by their position")
+                        );
+                    }
+                    let value = meta.value()?;
+                    if value.peek(LitStr) {
+                        let litstr: LitStr = value.parse()?;
+                        text_index = Some(litstr.value());
+                        return Ok(());
+                    }
+                    let int = parse_signed_index(value)?;
+                    if indices.contains(&int) || aliases.contains(&int) {
                         return Err(meta.error("This index has already been assigned"));
                     }
                     explicit_index = Some(int);
                     Ok(())
+                } else if meta.path.is_ident("alias") {
+                    let int = parse_signed_index(meta.value()?)?;
+                    if indices.contains(&int)
+                        || aliases.contains(&int)
+                        || explicit_index == Some(int)
+                    {
+                        return Err(meta.error("This index has already been assigned"));
+                    }
+                    aliases.push(int);
+                    Ok(())
+                } else if meta.path.is_ident("unknown") {
+                    unknown = true;
+                    Ok(())
+                } else if meta.path.is_ident("flatten") {
+                    flatten = true;
+                    Ok(())
                 } else {
-                    return Err(meta.error("Unkown field attribute"));
+                    Err(meta.error("Unkown field attribute"))
                 }
             })?;
         }
     }
 
-    if explicit_index.is_some() && skip_serializing_if.is_always() {
+    if (explicit_index.is_some() || text_index.is_some()) && skip_serializing_if.is_always() {
         return Err(Error::new_spanned(
             field,
             "`#[serde(index = ?]` and `#[serde(skip)]` cannot be combined",
         ));
     }
 
-    let index = if skip_serializing_if.is_always() {
+    if !aliases.is_empty() && (skip_serializing_if.is_always() || skip_deserializing) {
+        return Err(Error::new_spanned(
+            field,
+            "`#[serde(alias = ?]` cannot be combined with `#[serde(skip)]` or `#[serde(skip_deserializing)]`",
+        ));
+    }
+
+    if skip_serializing && !skip_serializing_if.is_none() {
+        return Err(Error::new_spanned(
+            field,
+            "`#[serde(skip_serializing)]` cannot be combined with `#[serde(skip)]` or `#[serde(skip_serializing_if = ?)]`",
+        ));
+    }
+
+    if skip_deserializing && skip_serializing_if.is_always() {
+        return Err(Error::new_spanned(
+            field,
+            "`#[serde(skip_deserializing)]` cannot be combined with `#[serde(skip)]`",
+        ));
+    }
+
+    if validate.is_some() && (skip_serializing_if.is_always() || skip_deserializing) {
+        return Err(Error::new_spanned(
+            field,
+            "`#[serde(validate = ?)]` cannot be combined with `#[serde(skip)]` or `#[serde(skip_deserializing)]`",
+        ));
+    }
+
+    if skip_serializing && skip_deserializing {
+        return Err(Error::new_spanned(
+            field,
+            "`#[serde(skip_serializing)]` and `#[serde(skip_deserializing)]` cannot be combined, use `#[serde(skip)]` instead",
+        ));
+    }
+
+    if unknown || flatten {
+        let attribute = if unknown {
+            "`#[serde_indexed(unknown)]`"
+        } else {
+            "`#[serde(flatten)]`"
+        };
+        if (unknown && flatten)
+            || explicit_index.is_some()
+            || text_index.is_some()
+            || !aliases.is_empty()
+            || !skip_serializing_if.is_none()
+            || skip_serializing
+            || skip_deserializing
+            || serialize_with.is_some()
+            || deserialize_with.is_some()
+            || validate.is_some()
+            || !default.is_none()
+        {
+            return Err(Error::new_spanned(
+                field,
+                format!("{attribute} cannot be combined with other field attributes"),
+            ));
+        }
+        // The catch-all and flattened fields do not have an index and do not increment the
+        // assigned index
+        no_increment = true;
+    }
+
+    let index = if skip_serializing_if.is_always() || unknown || flatten {
         None
-    } else if attrs.auto_index {
+    } else if attrs.auto_index || field.ident.is_none() {
+        // Automatically assigned indices can collide with the aliases of previous fields
+        if indices.contains(&auto_index) {
+            return Err(Error::new_spanned(
+                field,
+                format!("The index {auto_index} has already been assigned as an alias"),
+            ));
+        }
+        indices.push(auto_index);
         Some(auto_index)
     } else if let Some(index) = explicit_index {
         indices.push(index);
         Some(index)
+    } else if text_index.is_some() {
+        None
     } else {
         return Err(Error::new_spanned(
             field,
             "Field without index or skip attribute and `#[serde(auto_index)]` is not enabled on the struct",
         ));
     };
+    indices.extend(&aliases);
+
+    let (label, member) = match &field.ident {
+        Some(ident) => (ident.unraw().to_string(), syn::Member::Named(ident.clone())),
+        None => (
+            position.to_string(),
+            syn::Member::Unnamed(syn::Index {
+                index: position as u32,
+                span: field.span(),
+            }),
+        ),
+    };
+
     Ok(Field {
-        label: ident.to_string(),
-        member: syn::Member::Named(ident.clone()),
+        label,
+        member,
         index,
+        text_index,
+        aliases,
         ty: field.ty.clone(),
         skip_serializing_if,
+        skip_serializing,
+        skip_deserializing,
         serialize_with,
         deserialize_with,
+        default,
+        bound,
+        validate,
         no_increment,
+        unknown,
+        flatten,
         original_span: field.span(),
     })
 }
@@ -260,14 +673,351 @@ fn fields_from_ast(
 ) -> Result<Vec<Field>> {
     let mut indices = Vec::new();
     let mut index = 0;
-    fields
+    let fields = fields
         .iter()
-        .map(|field| {
-            let field = parse_field(attrs, index, field, &mut indices)?;
+        .enumerate()
+        .map(|(position, field)| {
+            let field = parse_field(attrs, index, position, field, &mut indices)?;
             if !field.no_increment {
                 index += 1;
             }
             Ok(field)
         })
-        .collect()
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut unknown_fields = fields.iter().filter(|field| field.unknown);
+    if let Some(field) = unknown_fields.nth(1) {
+        return Err(Error::new(
+            field.original_span,
+            "Only one field can collect the unknown entries",
+        ));
+    }
+    if let Some(field) = fields.iter().find(|field| field.unknown) {
+        if attrs.array || attrs.deny_unknown_fields {
+            return Err(Error::new(
+                field.original_span,
+                "`#[serde_indexed(unknown)]` cannot be used with array or deny_unknown_fields",
+            ));
+        }
+    }
+    if let Some(field) = fields.iter().find(|field| field.text_index.is_some()) {
+        if attrs.array
+            || attrs.canonical
+            || attrs.human_readable
+            || fields.iter().any(|field| field.unknown || field.flatten)
+        {
+            return Err(Error::new(
+                field.original_span,
+                "Text indices cannot be used with array, canonical, human_readable, `#[serde_indexed(unknown)]` or `#[serde(flatten)]`",
+            ));
+        }
+    }
+    for (position, field) in fields.iter().enumerate() {
+        if let Some(text_index) = &field.text_index {
+            if fields[..position]
+                .iter()
+                .any(|previous| previous.text_index.as_ref() == Some(text_index))
+            {
+                return Err(Error::new(
+                    field.original_span,
+                    "This index has already been assigned",
+                ));
+            }
+        }
+    }
+    // The entries of several flattened fields could not be sorted among each other
+    if let Some(field) = fields.iter().filter(|field| field.flatten).nth(1) {
+        return Err(Error::new(
+            field.original_span,
+            "Only one field can be flattened",
+        ));
+    }
+    if let Some(field) = fields.iter().find(|field| field.flatten) {
+        if attrs.array || attrs.human_readable {
+            return Err(Error::new(
+                field.original_span,
+                "`#[serde(flatten)]` cannot be used with array or human_readable",
+            ));
+        }
+    }
+    Ok(fields)
+}
+
+fn check_no_struct_attrs(attrs: &StructAttrs, kind: &str) -> Result<()> {
+    let unsupported = [
+        ("auto_index", attrs.auto_index),
+        ("offset", attrs.offset != 0),
+        ("array", attrs.array),
+        ("default", !attrs.default.is_none()),
+        ("deny_unknown_fields", attrs.deny_unknown_fields),
+        ("canonical", attrs.canonical),
+        ("human_readable", attrs.human_readable),
+    ];
+    for (name, is_set) in unsupported {
+        if is_set {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("{name} is not supported for {kind}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Newtype structs only support attributes that change the (de)serialization of the inner value.
+fn check_newtype_field(field: &Field) -> Result<()> {
+    if !field.skip_serializing_if.is_none()
+        || field.skip_serializing
+        || field.skip_deserializing
+        || !field.default.is_none()
+        || !field.aliases.is_empty()
+        || field.validate.is_some()
+        || field.unknown
+        || field.flatten
+    {
+        return Err(Error::new(
+            field.original_span,
+            "The field of a newtype struct only supports the with, serialize_with, deserialize_with and bound attributes",
+        ));
+    }
+    Ok(())
+}
+
+/// In array mode, the fields are serialized as positional elements sorted by their index.
+fn check_array_fields(fields: &[Field]) -> Result<()> {
+    let mut indices = Vec::new();
+    for field in fields {
+        if let Skip::If(_) = field.skip_serializing_if {
+            return Err(Error::new(
+                field.original_span,
+                "skip_serializing_if cannot be used in array mode",
+            ));
+        }
+        if field.skip_serializing || field.skip_deserializing {
+            return Err(Error::new(
+                field.original_span,
+                "skip_serializing and skip_deserializing cannot be used in array mode",
+            ));
+        }
+        if !field.aliases.is_empty() {
+            return Err(Error::new(
+                field.original_span,
+                "alias cannot be used in array mode",
+            ));
+        }
+        indices.extend(field.index);
+    }
+    indices.sort_unstable();
+    if indices.windows(2).any(|pair| pair[1] != pair[0] + 1) {
+        return Err(Error::new(
+            Span::call_site(),
+            "The indices of a struct in array mode must be contiguous",
+        ));
+    }
+    Ok(())
+}
+
+/// Parses an integer index that may be negative, e.g. `-7`.
+fn parse_signed_index(input: ParseStream) -> Result<i64> {
+    let negative = input.parse::<Option<Token![-]>>()?.is_some();
+    let litint: LitInt = input.parse()?;
+    let int: i64 = litint.base10_parse()?;
+    Ok(if negative { -int } else { int })
+}
+
+/// Evaluates an explicit discriminant if it is an integer literal, possibly negated.
+fn discriminant_value(expr: &syn::Expr) -> Option<i64> {
+    match expr {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Int(litint),
+            ..
+        }) => litint.base10_parse().ok(),
+        syn::Expr::Unary(syn::ExprUnary {
+            op: syn::UnOp::Neg(_),
+            expr,
+            ..
+        }) => discriminant_value(expr).map(|int| -int),
+        syn::Expr::Group(syn::ExprGroup { expr, .. })
+        | syn::Expr::Paren(syn::ExprParen { expr, .. }) => discriminant_value(expr),
+        _ => None,
+    }
+}
+
+/// The field of a newtype variant is either flattened into the map with the tag of an internally
+/// tagged enum or serialized as the value of the single entry of the map.
+fn check_variant_field(field: &Field) -> Result<()> {
+    if !field.skip_serializing_if.is_none()
+        || field.skip_serializing
+        || field.skip_deserializing
+        || !field.default.is_none()
+        || !field.aliases.is_empty()
+        || field.validate.is_some()
+        || field.serialize_with.is_some()
+        || field.deserialize_with.is_some()
+        || field.unknown
+        || field.flatten
+    {
+        return Err(Error::new(
+            field.original_span,
+            "The field of a newtype variant only supports the bound attribute",
+        ));
+    }
+    Ok(())
+}
+
+fn parse_variant(
+    variant: &syn::Variant,
+    discriminant: Option<i64>,
+    tagged: bool,
+    indices: &mut Vec<i64>,
+    labels: &mut Vec<String>,
+) -> Result<Variant> {
+    let mut explicit_index = None;
+    let mut other = false;
+    let mut label: Option<LitStr> = None;
+
+    for attr in &variant.attrs {
+        if attr.path().is_ident("serde") || attr.path().is_ident("serde_indexed") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("index") || meta.path.is_ident("tag_value") {
+                    if meta.path.is_ident("tag_value") && !tagged {
+                        return Err(meta.error("tag_value requires the tag attribute on the enum"));
+                    }
+                    if explicit_index.is_some() {
+                        return Err(meta.error("Multiple attributes for index or tag_value"));
+                    }
+                    explicit_index = Some(parse_signed_index(meta.value()?)?);
+                    Ok(())
+                } else if meta.path.is_ident("other") {
+                    if tagged {
+                        return Err(
+                            meta.error("other is not supported for internally tagged enums")
+                        );
+                    }
+                    other = true;
+                    Ok(())
+                } else if meta.path.is_ident("label") {
+                    if tagged {
+                        return Err(
+                            meta.error("label is not supported for internally tagged enums")
+                        );
+                    }
+                    if label.is_some() {
+                        return Err(meta.error("Multiple attributes for label"));
+                    }
+                    let litstr: LitStr = meta.value()?.parse()?;
+                    if labels.contains(&litstr.value()) {
+                        return Err(meta.error("This label has already been assigned"));
+                    }
+                    labels.push(litstr.value());
+                    label = Some(litstr);
+                    Ok(())
+                } else {
+                    Err(meta.error("Unknown variant attribute"))
+                }
+            })?;
+        }
+    }
+
+    if label.is_some() && !matches!(variant.fields, Fields::Unit) {
+        return Err(Error::new_spanned(
+            variant,
+            "label is only supported for unit variants",
+        ));
+    }
+
+    if other {
+        if explicit_index.is_some() {
+            return Err(Error::new_spanned(
+                variant,
+                "The other variant cannot have an index",
+            ));
+        }
+        if !matches!(&variant.fields, Fields::Unnamed(fields) if fields.unnamed.len() == 1) {
+            return Err(Error::new_spanned(
+                variant,
+                "The other variant must be a newtype variant with an i64 field",
+            ));
+        }
+        return Ok(Variant {
+            ident: variant.ident.clone(),
+            index: 0,
+            field: None,
+            other,
+            label,
+            original_span: variant.span(),
+        });
+    }
+
+    let field = match &variant.fields {
+        Fields::Unit => None,
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+            let field = parse_field(
+                &StructAttrs::default(),
+                0,
+                0,
+                &fields.unnamed[0],
+                &mut Vec::new(),
+            )?;
+            check_variant_field(&field)?;
+            Some(field)
+        }
+        _ => {
+            return Err(Error::new_spanned(
+                variant,
+                "Only unit and newtype variants are supported",
+            ));
+        }
+    };
+
+    let index = explicit_index.or(discriminant).ok_or_else(|| {
+        Error::new_spanned(
+            variant,
+            "Variant without integer literal discriminant requires the `#[serde(index = ?)]` attribute",
+        )
+    })?;
+    if indices.contains(&index) {
+        return Err(Error::new_spanned(
+            variant,
+            "This index has already been assigned",
+        ));
+    }
+    indices.push(index);
+
+    Ok(Variant {
+        ident: variant.ident.clone(),
+        index,
+        field,
+        other,
+        label,
+        original_span: variant.span(),
+    })
+}
+
+fn variants_from_ast(
+    variants: &syn::punctuated::Punctuated<syn::Variant, Token![,]>,
+    tagged: bool,
+) -> Result<Vec<Variant>> {
+    let mut indices = Vec::new();
+    let mut labels = Vec::new();
+    // Mirrors the implicit discriminants assigned by the compiler
+    let mut discriminant = Some(0);
+    let variants = variants
+        .iter()
+        .map(|variant| {
+            if let Some((_, expr)) = &variant.discriminant {
+                discriminant = discriminant_value(expr);
+            }
+            let variant = parse_variant(variant, discriminant, tagged, &mut indices, &mut labels)?;
+            discriminant = discriminant.and_then(|int| int.checked_add(1));
+            Ok(variant)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(variant) = variants.iter().filter(|variant| variant.other).nth(1) {
+        return Err(Error::new(
+            variant.original_span,
+            "Only one variant can have the other attribute",
+        ));
+    }
+    Ok(variants)
 }
@@ -68,6 +68,7 @@ mod some_keys {
         pub vector: &'c Bytes,
     }
 
+    #[allow(dead_code)]
     #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
     // #[serde_indexed(offset = 1)]
     #[serde_indexed(auto_index)]
@@ -77,6 +78,7 @@ mod some_keys {
         pub key: bool,
     }
 
+    #[allow(dead_code)]
     #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
     // #[serde_indexed(offset = 1)]
     #[serde_indexed(auto_index)]
@@ -86,6 +88,7 @@ mod some_keys {
         pub key: bool,
     }
 
+    #[allow(dead_code)]
     #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
     // #[serde_indexed(offset = 1)]
     #[serde_indexed(auto_index)]
@@ -648,10 +651,10 @@ mod index {
             &indices_example(),
             &[
                 Token::Map { len: Some(3) },
-                Token::U64(9),
-                Token::U64(42),
                 Token::U64(2),
                 Token::U64(1),
+                Token::U64(9),
+                Token::U64(42),
                 Token::U64(0x5A),
                 Token::U64(99),
                 Token::MapEnd,
@@ -659,3 +662,2280 @@ mod index {
         );
     }
 }
+
+mod enums {
+    use super::*;
+    use serde_test::assert_de_tokens_error;
+
+    #[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    enum Algorithm {
+        Es256 = -7,
+        EdDsa = -8,
+        Totp = -9,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    enum CredentialProtection {
+        #[serde(index = 1)]
+        Optional,
+        #[serde(index = 2)]
+        OptionalWithCredentialIdList,
+        #[serde(index = 3)]
+        Required,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    enum Implicit {
+        Zero,
+        Five = 5,
+        Six,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    enum Renamed {
+        #[serde_indexed(index = 5)]
+        A = 1,
+        B = 2,
+    }
+
+    #[test]
+    fn tokens() {
+        assert_tokens(&Algorithm::Es256, &[Token::I64(-7)]);
+        assert_tokens(&Algorithm::Totp, &[Token::I64(-9)]);
+        assert_tokens(&CredentialProtection::Optional, &[Token::U64(1)]);
+        assert_tokens(&CredentialProtection::Required, &[Token::U64(3)]);
+        assert_tokens(&Implicit::Zero, &[Token::U64(0)]);
+        assert_tokens(&Implicit::Six, &[Token::U64(6)]);
+        assert_tokens(&Renamed::A, &[Token::U64(5)]);
+        assert_tokens(&Renamed::B, &[Token::U64(2)]);
+    }
+
+    #[test]
+    fn cbor() {
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&Algorithm::EdDsa, &mut buf).unwrap();
+        assert_eq!(&buf[..size], &[0x27]);
+        let deserialized: Algorithm = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, Algorithm::EdDsa);
+
+        let size = cbor_serialize(&CredentialProtection::Required, &mut buf).unwrap();
+        assert_eq!(&buf[..size], &[0x03]);
+        let deserialized: CredentialProtection = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, CredentialProtection::Required);
+    }
+
+    #[test]
+    fn unknown_index() {
+        assert_de_tokens_error::<Algorithm>(
+            &[Token::I64(-257)],
+            "invalid value: integer `-257`, expected an index of Algorithm",
+        );
+        assert_de_tokens_error::<CredentialProtection>(
+            &[Token::U64(4)],
+            "invalid value: integer `4`, expected an index of CredentialProtection",
+        );
+    }
+}
+
+mod negative_index {
+    use super::*;
+    use hex_literal::hex;
+    use serde_bytes::ByteArray;
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    struct P256PublicKey {
+        #[serde(index = 1)]
+        kty: i8,
+        #[serde(index = 3)]
+        alg: i8,
+        #[serde(index = -1)]
+        crv: i8,
+        #[serde(index = -2)]
+        x: ByteArray<4>,
+        #[serde(index = -3)]
+        y: ByteArray<4>,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(auto_index, offset = -2)]
+    struct NegativeOffset {
+        a: u8,
+        b: u8,
+        c: u8,
+    }
+
+    fn key_example() -> P256PublicKey {
+        P256PublicKey {
+            kty: 2,
+            alg: -7,
+            crv: 1,
+            x: ByteArray::new([1, 2, 3, 4]),
+            y: ByteArray::new([5, 6, 7, 8]),
+        }
+    }
+
+    // in Python: cbor2.dumps({1: 2, 3: -7, -1: 1, -2: bytes([1, 2, 3, 4]), -3: bytes([5, 6, 7, 8])})
+    const SERIALIZED_KEY_EXAMPLE: &[u8] = &hex!("a5010203262001214401020304224405060708");
+
+    #[test]
+    fn tokens() {
+        assert_tokens(
+            &key_example(),
+            &[
+                Token::Map { len: Some(5) },
+                Token::I64(1),
+                Token::I8(2),
+                Token::I64(3),
+                Token::I8(-7),
+                Token::I64(-1),
+                Token::I8(1),
+                Token::I64(-2),
+                Token::Bytes(&[1, 2, 3, 4]),
+                Token::I64(-3),
+                Token::Bytes(&[5, 6, 7, 8]),
+                Token::MapEnd,
+            ],
+        );
+        assert_tokens(
+            &NegativeOffset { a: 1, b: 2, c: 3 },
+            &[
+                Token::Map { len: Some(3) },
+                Token::I64(0),
+                Token::U8(3),
+                Token::I64(-1),
+                Token::U8(2),
+                Token::I64(-2),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn cbor() {
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&key_example(), &mut buf).unwrap();
+        assert_eq!(&buf[..size], SERIALIZED_KEY_EXAMPLE);
+
+        let deserialized: P256PublicKey =
+            cbor_deserialize_with_scratch(SERIALIZED_KEY_EXAMPLE, &mut []).unwrap();
+        assert_eq!(deserialized, key_example());
+    }
+}
+
+mod array {
+    use super::*;
+    use hex_literal::hex;
+    use serde_test::assert_de_tokens_error;
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(array)]
+    struct Sign1 {
+        #[serde(index = 3)]
+        #[serde(with = "serde_bytes")]
+        signature: Vec<u8>,
+        #[serde(index = 0)]
+        #[serde(with = "serde_bytes")]
+        protected: Vec<u8>,
+        #[serde(skip)]
+        verified: bool,
+        #[serde(index = 1)]
+        unprotected: Option<u8>,
+        #[serde(index = 2)]
+        #[serde(with = "serde_bytes")]
+        payload: Vec<u8>,
+    }
+
+    fn sign1_example() -> Sign1 {
+        Sign1 {
+            signature: vec![4, 5],
+            protected: vec![1],
+            verified: false,
+            unprotected: None,
+            payload: vec![2, 3],
+        }
+    }
+
+    // in Python: cbor2.dumps([bytes([1]), None, bytes([2, 3]), bytes([4, 5])])
+    const SERIALIZED_SIGN1_EXAMPLE: &[u8] = &hex!("844101f6420203420405");
+
+    #[test]
+    fn tokens() {
+        assert_tokens(
+            &sign1_example(),
+            &[
+                Token::Tuple { len: 4 },
+                Token::Bytes(&[1]),
+                Token::None,
+                Token::Bytes(&[2, 3]),
+                Token::Bytes(&[4, 5]),
+                Token::TupleEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn cbor() {
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&sign1_example(), &mut buf).unwrap();
+        assert_eq!(&buf[..size], SERIALIZED_SIGN1_EXAMPLE);
+
+        let deserialized: Sign1 =
+            cbor_deserialize_with_scratch(SERIALIZED_SIGN1_EXAMPLE, &mut []).unwrap();
+        assert_eq!(deserialized, sign1_example());
+    }
+
+    #[test]
+    fn missing_element() {
+        assert_de_tokens_error::<Sign1>(
+            &[
+                Token::Tuple { len: 2 },
+                Token::Bytes(&[1]),
+                Token::None,
+                Token::TupleEnd,
+            ],
+            "invalid length 2, expected Sign1",
+        );
+    }
+}
+
+mod default {
+    use super::*;
+    use serde_test::assert_de_tokens;
+
+    #[derive(Default, PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    struct Options {
+        #[serde(index = 1)]
+        rk: bool,
+        #[serde(index = 2)]
+        uv: bool,
+    }
+
+    fn default_pin_protocol() -> u8 {
+        1
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    struct MakeCredential {
+        #[serde(index = 1)]
+        rp: u8,
+        #[serde(index = 2, default)]
+        options: Options,
+        #[serde(index = 3, default = "default_pin_protocol")]
+        pin_protocol: u8,
+        #[serde(skip, default = "default_pin_protocol")]
+        skipped: u8,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(array, auto_index)]
+    struct WithTrailingDefault {
+        first: u8,
+        #[serde(default = "default_pin_protocol")]
+        second: u8,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(default)]
+    struct Config {
+        #[serde(index = 1)]
+        retries: u8,
+        #[serde(index = 2)]
+        timeout: u16,
+        #[serde(index = 3, default)]
+        verbose: bool,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Self {
+                retries: 3,
+                timeout: 1000,
+                verbose: true,
+            }
+        }
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde(default = "default_limits")]
+    struct Limits {
+        #[serde(index = 1)]
+        min: u8,
+        #[serde(index = 2)]
+        max: u8,
+    }
+
+    fn default_limits() -> Limits {
+        Limits { min: 1, max: 64 }
+    }
+
+    #[test]
+    fn missing_fields() {
+        assert_de_tokens(
+            &MakeCredential {
+                rp: 42,
+                options: Options::default(),
+                pin_protocol: 1,
+                skipped: 1,
+            },
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(1),
+                Token::U8(42),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens(
+            &WithTrailingDefault {
+                first: 42,
+                second: 1,
+            },
+            &[Token::Tuple { len: 1 }, Token::U8(42), Token::TupleEnd],
+        );
+    }
+
+    #[test]
+    fn container_default() {
+        assert_de_tokens(
+            &Config {
+                retries: 5,
+                timeout: 1000,
+                verbose: false,
+            },
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(1),
+                Token::U8(5),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens(
+            &Limits { min: 1, max: 16 },
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(2),
+                Token::U8(16),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn present_fields() {
+        assert_tokens(
+            &MakeCredential {
+                rp: 42,
+                options: Options {
+                    rk: true,
+                    uv: false,
+                },
+                pin_protocol: 2,
+                skipped: 1,
+            },
+            &[
+                Token::Map { len: Some(3) },
+                Token::U64(1),
+                Token::U8(42),
+                Token::U64(2),
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::Bool(true),
+                Token::U64(2),
+                Token::Bool(false),
+                Token::MapEnd,
+                Token::U64(3),
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+    }
+}
+
+mod deny_unknown_fields {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error};
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(deny_unknown_fields)]
+    struct Strict {
+        #[serde(index = 3)]
+        c: u8,
+        #[serde(index = 1)]
+        a: u8,
+        #[serde(skip)]
+        b: u8,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde(deny_unknown_fields)]
+    struct Empty {}
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(array, auto_index, deny_unknown_fields)]
+    struct StrictArray {
+        a: u8,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(array, auto_index)]
+    struct LenientArray {
+        a: u8,
+    }
+
+    #[test]
+    fn known_fields() {
+        assert_tokens(
+            &Strict { c: 3, a: 1, b: 0 },
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::U64(3),
+                Token::U8(3),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn unknown_fields() {
+        assert_de_tokens_error::<Strict>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::U64(2),
+            ],
+            "unknown index `2`, expected one of `1`, `3`",
+        );
+        assert_de_tokens_error::<Empty>(
+            &[Token::Map { len: Some(1) }, Token::U64(0)],
+            "unknown index `0`, there are no fields",
+        );
+    }
+
+    #[test]
+    fn trailing_elements() {
+        assert_de_tokens_error::<StrictArray>(
+            &[Token::Tuple { len: 2 }, Token::U8(1), Token::U8(2)],
+            "invalid length 2, expected StrictArray",
+        );
+        assert_de_tokens(
+            &LenientArray { a: 1 },
+            &[
+                Token::Tuple { len: 2 },
+                Token::U8(1),
+                Token::U8(2),
+                Token::TupleEnd,
+            ],
+        );
+    }
+}
+
+mod canonical {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error, assert_ser_tokens};
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(canonical)]
+    struct Request {
+        #[serde(index = 1)]
+        a: u8,
+        #[serde(index = 2)]
+        b: u8,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(canonical)]
+    struct Key {
+        #[serde(index = 1)]
+        kty: u8,
+        #[serde(index = 24)]
+        large: u8,
+        #[serde(index = -1)]
+        crv: u8,
+        #[serde(index = -2)]
+        x: u8,
+    }
+
+    #[test]
+    fn canonical_order() {
+        assert_de_tokens(
+            &Request { a: 1, b: 2 },
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::U64(2),
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+        // Unsigned keys come before negative keys, independent of the length of their encoding
+        assert_de_tokens(
+            &Key {
+                kty: 1,
+                large: 24,
+                crv: 1,
+                x: 2,
+            },
+            &[
+                Token::Map { len: Some(4) },
+                Token::I64(1),
+                Token::U8(1),
+                Token::I64(24),
+                Token::U8(24),
+                Token::I64(-1),
+                Token::U8(1),
+                Token::I64(-2),
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn serialize_canonical_order() {
+        assert_ser_tokens(
+            &Key {
+                kty: 1,
+                large: 24,
+                crv: 1,
+                x: 2,
+            },
+            &[
+                Token::Map { len: Some(4) },
+                Token::I64(1),
+                Token::U8(1),
+                Token::I64(24),
+                Token::U8(24),
+                Token::I64(-1),
+                Token::U8(1),
+                Token::I64(-2),
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn non_canonical_order() {
+        assert_de_tokens_error::<Request>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(2),
+                Token::U8(2),
+                Token::U64(1),
+            ],
+            "index `1` is not in canonical order after index `2`",
+        );
+        assert_de_tokens_error::<Request>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::U64(1),
+            ],
+            "index `1` is not in canonical order after index `1`",
+        );
+        assert_de_tokens_error::<Key>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(-1),
+                Token::U8(1),
+                Token::I64(1),
+            ],
+            "index `1` is not in canonical order after index `-1`",
+        );
+        assert_de_tokens_error::<Key>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(-2),
+                Token::U8(2),
+                Token::I64(24),
+            ],
+            "index `24` is not in canonical order after index `-2`",
+        );
+        assert_de_tokens_error::<Key>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(-1),
+                Token::U8(1),
+                Token::I64(24),
+            ],
+            "index `24` is not in canonical order after index `-1`",
+        );
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(canonical)]
+    struct Large {
+        #[serde(index = 100)]
+        a: u8,
+        #[serde(index = -1)]
+        b: u8,
+    }
+
+    #[test]
+    fn bytewise_order() {
+        // 100 is encoded in two bytes and -1 in one byte, but unsigned keys come first
+        let mut input = [0xa2, 0x18, 0x64, 0x01, 0x20, 0x02];
+        let deserialized: Large = cbor_deserialize(&mut input).unwrap();
+        assert_eq!(deserialized, Large { a: 1, b: 2 });
+
+        let mut input = [0xa2, 0x20, 0x02, 0x18, 0x64, 0x01];
+        let error = cbor_deserialize::<Large>(&mut input).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("index `100` is not in canonical order after index `-1`"));
+    }
+
+    #[test]
+    fn serialize_bytewise_order() {
+        let mut buf = [0u8; 16];
+        let size = cbor_serialize(&Large { a: 1, b: 2 }, &mut buf).unwrap();
+        assert_eq!(&buf[..size], [0xa2, 0x18, 0x64, 0x01, 0x20, 0x02]);
+    }
+}
+
+mod unknown {
+    use super::*;
+    use serde_test::assert_de_tokens_error;
+    use std::collections::BTreeMap;
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    struct Extensions {
+        #[serde(index = 1)]
+        cred_protect: u8,
+        #[serde_indexed(unknown)]
+        unknown: BTreeMap<i64, u8>,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(canonical)]
+    struct CanonicalExtensions {
+        #[serde(index = 1)]
+        cred_protect: u8,
+        #[serde(index = 5)]
+        min_pin_length: u8,
+        #[serde_indexed(unknown)]
+        unknown: BTreeMap<i64, u8>,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(auto_index)]
+    struct VendorCommand {
+        command: u8,
+        #[serde_indexed(unknown)]
+        unknown: heapless::LinearMap<i64, u8, 2>,
+        parameter: u8,
+    }
+
+    #[test]
+    fn round_trip() {
+        // The unknown entries are serialized in canonical order between the known fields
+        let value = Extensions {
+            cred_protect: 2,
+            unknown: [(-5, 1), (3, 2), (0, 4)].into_iter().collect(),
+        };
+        assert_tokens(
+            &value,
+            &[
+                Token::Map { len: Some(4) },
+                Token::I64(0),
+                Token::U8(4),
+                Token::I64(1),
+                Token::U8(2),
+                Token::I64(3),
+                Token::U8(2),
+                Token::I64(-5),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+
+        let value = VendorCommand {
+            command: 1,
+            unknown: [(5, 5)].into_iter().collect(),
+            parameter: 2,
+        };
+        assert_tokens(
+            &value,
+            &[
+                Token::Map { len: Some(3) },
+                Token::I64(0),
+                Token::U8(1),
+                Token::I64(1),
+                Token::U8(2),
+                Token::I64(5),
+                Token::U8(5),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn canonical_round_trip() {
+        let value = CanonicalExtensions {
+            cred_protect: 2,
+            min_pin_length: 4,
+            unknown: [(3, 1), (-1, 7), (24, 9)].into_iter().collect(),
+        };
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&value, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..size],
+            [0xa5, 0x01, 0x02, 0x03, 0x01, 0x05, 0x04, 0x18, 0x18, 0x09, 0x20, 0x07]
+        );
+        let deserialized: CanonicalExtensions = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, value);
+    }
+
+    #[test]
+    fn insert_errors() {
+        assert_de_tokens_error::<Extensions>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(2),
+                Token::U8(1),
+                Token::I64(2),
+                Token::U8(1),
+            ],
+            "duplicate index `2`",
+        );
+        assert_de_tokens_error::<VendorCommand>(
+            &[
+                Token::Map { len: Some(3) },
+                Token::I64(2),
+                Token::U8(1),
+                Token::I64(3),
+                Token::U8(1),
+                Token::I64(4),
+                Token::U8(1),
+            ],
+            "too many unknown entries to insert index `4`",
+        );
+    }
+}
+
+mod human_readable {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error, Configure};
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(human_readable)]
+    struct Request {
+        #[serde(index = 1)]
+        client_data_hash: u8,
+        #[serde(index = -2, skip_serializing_if = "Option::is_none")]
+        pin_protocol: Option<u8>,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(human_readable, deny_unknown_fields)]
+    struct Strict {
+        #[serde(index = 1)]
+        a: u8,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(human_readable)]
+    struct Raw {
+        #[serde(index = 1)]
+        r#type: u8,
+    }
+
+    fn request_example() -> Request {
+        Request {
+            client_data_hash: 42,
+            pin_protocol: Some(1),
+        }
+    }
+
+    #[test]
+    fn readable() {
+        assert_tokens(
+            &request_example().readable(),
+            &[
+                Token::Map { len: Some(2) },
+                Token::Str("client_data_hash"),
+                Token::U8(42),
+                Token::Str("pin_protocol"),
+                Token::Some,
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+        // JSON only supports string keys
+        assert_de_tokens(
+            &request_example().readable(),
+            &[
+                Token::Map { len: Some(2) },
+                Token::Str("1"),
+                Token::U8(42),
+                Token::Str("-2"),
+                Token::Some,
+                Token::U8(1),
+                Token::Str("unknown"),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn compact() {
+        assert_tokens(
+            &request_example().compact(),
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(1),
+                Token::U8(42),
+                Token::I64(-2),
+                Token::Some,
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&request_example(), &mut buf).unwrap();
+        // in Python: cbor2.dumps({1: 42, -2: 1})
+        assert_eq!(&buf[..size], b"\xa2\x01\x18\x2a\x21\x01");
+        let deserialized: Request = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, request_example());
+    }
+
+    #[test]
+    fn raw_identifier() {
+        assert_tokens(
+            &Raw { r#type: 3 }.readable(),
+            &[
+                Token::Map { len: Some(1) },
+                Token::Str("type"),
+                Token::U8(3),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn unknown_label() {
+        assert_de_tokens_error::<Strict>(
+            &[Token::Map { len: Some(1) }, Token::Str("b")],
+            "unknown field `b`, expected `a`",
+        );
+    }
+}
+
+mod tuple {
+    use super::*;
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(offset = 1)]
+    pub struct Pair(
+        u8,
+        #[serde(skip_serializing_if = "Option::is_none")] Option<u8>,
+    );
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct WithSkip(u8, #[serde(skip)] u8, u8);
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Wrapper(Pair);
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Bytes(#[serde(with = "serde_bytes")] Vec<u8>);
+
+    #[test]
+    fn tuple_struct() {
+        assert_tokens(
+            &Pair(1, Some(2)),
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::U64(2),
+                Token::Some,
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+        assert_tokens(
+            &Pair(1, None),
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+        assert_tokens(
+            &WithSkip(1, 0, 3),
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(0),
+                Token::U8(1),
+                Token::U64(2),
+                Token::U8(3),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn newtype_struct() {
+        assert_tokens(
+            &Wrapper(Pair(1, None)),
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&Bytes(vec![1, 2, 3]), &mut buf).unwrap();
+        // in Python: cbor2.dumps(b"\x01\x02\x03")
+        assert_eq!(&buf[..size], b"\x43\x01\x02\x03");
+        let deserialized: Bytes = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, Bytes(vec![1, 2, 3]));
+    }
+}
+
+mod alias {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error};
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Request {
+        #[serde(index = 0x01, alias = 0x41)]
+        pub client_data_hash: u8,
+        #[serde(index = 0x02, alias = -1, alias = 0x42)]
+        pub pin_protocol: u8,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(auto_index)]
+    pub struct AutoIndex {
+        #[serde(alias = 5)]
+        pub a: u8,
+        pub b: u8,
+    }
+
+    #[test]
+    fn serialize_primary_index() {
+        assert_tokens(
+            &Request {
+                client_data_hash: 1,
+                pin_protocol: 2,
+            },
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(1),
+                Token::U8(1),
+                Token::I64(2),
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+        assert_tokens(
+            &AutoIndex { a: 1, b: 2 },
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(0),
+                Token::U8(1),
+                Token::U64(1),
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn deserialize_alias() {
+        assert_de_tokens(
+            &Request {
+                client_data_hash: 1,
+                pin_protocol: 2,
+            },
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(0x41),
+                Token::U8(1),
+                Token::I64(-1),
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens(
+            &AutoIndex { a: 1, b: 2 },
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(2),
+                Token::U64(5),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn duplicate_alias() {
+        assert_de_tokens_error::<Request>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(2),
+                Token::U8(2),
+                Token::I64(0x42),
+                Token::U8(2),
+            ],
+            "duplicate field `pin_protocol`",
+        );
+    }
+}
+
+mod crate_path {
+    use super::*;
+
+    /// Shadows the serde crate, so the generated code must use the path given by `crate`.
+    #[allow(dead_code)]
+    mod serde {}
+
+    mod exports {
+        pub use ::serde as serde_reexport;
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(crate = "exports::serde_reexport")]
+    pub struct Reexport {
+        #[serde(index = 1)]
+        pub a: u8,
+        #[serde(index = -2, default, skip_serializing_if = "Option::is_none")]
+        pub b: Option<u8>,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(crate = "::serde")]
+    pub enum Algorithm {
+        Es256 = -7,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(crate = "::serde")]
+    pub struct Newtype(#[serde(with = "serde_bytes")] Vec<u8>);
+
+    #[test]
+    fn reexported_serde() {
+        assert_tokens(
+            &Reexport { a: 1, b: Some(2) },
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(1),
+                Token::U8(1),
+                Token::I64(-2),
+                Token::Some,
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+        assert_tokens(&Algorithm::Es256, &[Token::I64(-7)]);
+        assert_tokens(&Newtype(vec![1]), &[Token::Bytes(&[1])]);
+    }
+}
+
+mod bound {
+    use super::*;
+    use core::fmt::Display;
+    use core::marker::PhantomData;
+    use core::str::FromStr;
+    use serde::de::Error as _;
+
+    #[derive(PartialEq, Debug, Default)]
+    pub struct NotSerde;
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde(bound = "")]
+    pub struct Marker<T> {
+        #[serde(index = 1)]
+        pub value: u8,
+        #[serde(skip)]
+        pub marker: PhantomData<T>,
+    }
+
+    fn serialize_display<T: Display, S: serde::Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    fn deserialize_from_str<'de, T: FromStr, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let value: String = serde::Deserialize::deserialize(deserializer)?;
+        value.parse().map_err(|_| D::Error::custom("invalid value"))
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Text<T, U> {
+        #[serde(index = 1)]
+        #[serde(bound(serialize = "T: Display", deserialize = "T: FromStr"))]
+        #[serde(serialize_with = "serialize_display")]
+        #[serde(deserialize_with = "deserialize_from_str")]
+        pub value: T,
+        #[serde(index = 2)]
+        pub other: U,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Inferred<T, U> {
+        #[serde(index = 1)]
+        pub value: u8,
+        #[serde(index = 2)]
+        pub marker: PhantomData<T>,
+        #[serde(skip)]
+        pub cache: Option<U>,
+    }
+
+    #[test]
+    fn inferred_bound() {
+        assert_tokens(
+            &Inferred::<NotSerde, NotSerde> {
+                value: 1,
+                marker: PhantomData,
+                cache: None,
+            },
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::U64(2),
+                Token::UnitStruct {
+                    name: "PhantomData",
+                },
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Skipped<T> {
+        #[serde(index = 1)]
+        pub value: u8,
+        #[serde(skip)]
+        pub state: T,
+    }
+
+    #[test]
+    fn default_bound() {
+        // `T` is only bound by `Default` because the skipped field is set to its default value
+        assert_tokens(
+            &Skipped::<NotSerde> {
+                value: 1,
+                state: NotSerde,
+            },
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    pub trait Proto {
+        type Param;
+    }
+
+    #[derive(PartialEq, Debug)]
+    pub struct V1;
+
+    impl Proto for V1 {
+        type Param = u8;
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Request<P: Proto> {
+        #[serde(index = 1)]
+        pub param: P::Param,
+    }
+
+    #[test]
+    fn associated_type_bound() {
+        // The bound is added for `P::Param`, not for `P`, which does not implement serde traits
+        assert_tokens(
+            &Request::<V1> { param: 3 },
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(1),
+                Token::U8(3),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn container_bound() {
+        assert_tokens(
+            &Marker::<NotSerde> {
+                value: 1,
+                marker: PhantomData,
+            },
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn field_bound() {
+        assert_tokens(
+            &Text {
+                value: core::net::Ipv4Addr::LOCALHOST,
+                other: 2u8,
+            },
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::Str("127.0.0.1"),
+                Token::U64(2),
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+    }
+}
+
+mod skip_direction {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error, assert_ser_tokens};
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Response {
+        #[serde(index = 1)]
+        pub value: u8,
+        #[serde(index = 2, skip_deserializing)]
+        pub computed: u8,
+        #[serde(index = 3, skip_serializing)]
+        pub deprecated: Option<u8>,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(auto_index, deny_unknown_fields)]
+    pub struct AutoIndex {
+        pub a: u8,
+        #[serde(skip_deserializing)]
+        pub b: u8,
+        pub c: u8,
+    }
+
+    #[test]
+    fn skip_serializing() {
+        assert_ser_tokens(
+            &Response {
+                value: 1,
+                computed: 2,
+                deprecated: Some(3),
+            },
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::U64(2),
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens(
+            &Response {
+                value: 1,
+                computed: 0,
+                deprecated: Some(3),
+            },
+            &[
+                Token::Map { len: Some(3) },
+                Token::U64(1),
+                Token::U8(1),
+                Token::U64(2),
+                Token::U8(2),
+                Token::U64(3),
+                Token::Some,
+                Token::U8(3),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn skip_deserializing() {
+        assert_ser_tokens(
+            &AutoIndex { a: 1, b: 2, c: 3 },
+            &[
+                Token::Map { len: Some(3) },
+                Token::U64(0),
+                Token::U8(1),
+                Token::U64(1),
+                Token::U8(2),
+                Token::U64(2),
+                Token::U8(3),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens(
+            &AutoIndex { a: 1, b: 0, c: 3 },
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(0),
+                Token::U8(1),
+                Token::U64(2),
+                Token::U8(3),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens_error::<AutoIndex>(
+            &[Token::Map { len: Some(1) }, Token::U64(1), Token::U8(2)],
+            "unknown index `1`, expected one of `0`, `2`",
+        );
+    }
+}
+
+mod validate {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error};
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(validate = "Self::check")]
+    pub struct Request {
+        #[serde(index = 1, skip_serializing_if = "Option::is_none")]
+        pub pin_auth: Option<u8>,
+        #[serde(index = 2, skip_serializing_if = "Option::is_none")]
+        pub pin_protocol: Option<u8>,
+    }
+
+    impl Request {
+        fn check(&self) -> Result<(), &'static str> {
+            if self.pin_auth.is_some() && self.pin_protocol.is_none() {
+                Err("pin_auth requires pin_protocol")
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn check_len(value: &RpId) -> Result<(), String> {
+        if value.0.len() > 4 {
+            Err(format!("rp id too long: {}", value.0.len()))
+        } else {
+            Ok(())
+        }
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(validate = "check_len")]
+    pub struct RpId(String);
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(array, validate = "Range::check")]
+    pub struct Range(u8, u8);
+
+    impl Range {
+        fn check(&self) -> Result<(), &'static str> {
+            if self.0 <= self.1 {
+                Ok(())
+            } else {
+                Err("empty range")
+            }
+        }
+    }
+
+    fn check_pin_protocol(value: &u8) -> Result<(), &'static str> {
+        match value {
+            1 | 2 => Ok(()),
+            _ => Err("unsupported PIN protocol"),
+        }
+    }
+
+    fn check_rp_id(value: &str) -> Result<(), String> {
+        if value.len() > 4 {
+            Err(format!("too long: {}", value.len()))
+        } else {
+            Ok(())
+        }
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Fields {
+        #[serde(index = 1, validate = "check_rp_id")]
+        pub rp_id: String,
+        #[serde(index = -2, alias = 3, validate = "check_pin_protocol")]
+        pub pin_protocol: u8,
+    }
+
+    #[derive(PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(array)]
+    pub struct Elements(u8, #[serde(validate = "check_pin_protocol")] u8);
+
+    #[test]
+    fn field_validators() {
+        assert_tokens(
+            &Fields {
+                rp_id: "test".to_owned(),
+                pin_protocol: 1,
+            },
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(1),
+                Token::Str("test"),
+                Token::I64(-2),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens_error::<Fields>(
+            &[
+                Token::Map { len: Some(1) },
+                Token::I64(1),
+                Token::Str("example"),
+            ],
+            "invalid value for field `rp_id` at index `1`: too long: 7",
+        );
+        assert_de_tokens_error::<Fields>(
+            &[Token::Map { len: Some(1) }, Token::I64(3), Token::U8(3)],
+            "invalid value for field `pin_protocol` at index `-2`: unsupported PIN protocol",
+        );
+        assert_de_tokens_error::<Elements>(
+            &[Token::Tuple { len: 2 }, Token::U8(0), Token::U8(3)],
+            "invalid value for field `1` at position `1`: unsupported PIN protocol",
+        );
+    }
+
+    #[test]
+    fn valid() {
+        assert_de_tokens(
+            &Request {
+                pin_auth: Some(1),
+                pin_protocol: Some(2),
+            },
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::Some,
+                Token::U8(1),
+                Token::U64(2),
+                Token::Some,
+                Token::U8(2),
+                Token::MapEnd,
+            ],
+        );
+        assert_tokens(&RpId("test".to_owned()), &[Token::Str("test")]);
+        assert_tokens(
+            &Range(1, 2),
+            &[
+                Token::Tuple { len: 2 },
+                Token::U8(1),
+                Token::U8(2),
+                Token::TupleEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_de_tokens_error::<Request>(
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(1),
+                Token::Some,
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+            "pin_auth requires pin_protocol",
+        );
+        assert_de_tokens_error::<RpId>(&[Token::Str("example")], "rp id too long: 7");
+        assert_de_tokens_error::<Range>(
+            &[
+                Token::Tuple { len: 2 },
+                Token::U8(2),
+                Token::U8(1),
+                Token::TupleEnd,
+            ],
+            "empty range",
+        );
+    }
+}
+
+mod conversion {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error};
+
+    #[derive(Clone, PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    pub struct Wire {
+        #[serde(index = 1)]
+        pub min: u8,
+        #[serde(index = 2)]
+        pub max: u8,
+    }
+
+    /// A range that is never empty, (de)serialized as `Wire`.
+    #[derive(Clone, PartialEq, Debug, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(try_from = "Wire", into = "Wire")]
+    pub struct Range {
+        start: u8,
+        end: u8,
+    }
+
+    impl TryFrom<Wire> for Range {
+        type Error = &'static str;
+
+        fn try_from(wire: Wire) -> Result<Self, Self::Error> {
+            if wire.min <= wire.max {
+                Ok(Self {
+                    start: wire.min,
+                    end: wire.max,
+                })
+            } else {
+                Err("empty range")
+            }
+        }
+    }
+
+    impl From<Range> for Wire {
+        fn from(range: Range) -> Self {
+            Self {
+                min: range.start,
+                max: range.end,
+            }
+        }
+    }
+
+    #[derive(PartialEq, Debug, DeserializeIndexed)]
+    #[serde_indexed(from = "Wire")]
+    pub struct Width(u8);
+
+    impl From<Wire> for Width {
+        fn from(wire: Wire) -> Self {
+            Self(wire.max.saturating_sub(wire.min))
+        }
+    }
+
+    const WIRE: &[Token] = &[
+        Token::Map { len: Some(2) },
+        Token::U64(1),
+        Token::U8(1),
+        Token::U64(2),
+        Token::U8(3),
+        Token::MapEnd,
+    ];
+
+    #[test]
+    fn try_from_into() {
+        assert_tokens(&Range { start: 1, end: 3 }, WIRE);
+        assert_de_tokens_error::<Range>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(3),
+                Token::U64(2),
+                Token::U8(1),
+                Token::MapEnd,
+            ],
+            "empty range",
+        );
+    }
+
+    #[test]
+    fn from() {
+        assert_de_tokens(&Width(2), WIRE);
+    }
+}
+
+mod flatten {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub struct Common {
+        #[serde(index = 1)]
+        pub version: u8,
+        #[serde(index = 3, skip_serializing_if = "Option::is_none")]
+        pub flags: Option<u8>,
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub struct Response {
+        #[serde(index = 4)]
+        pub data: u8,
+        #[serde(flatten)]
+        pub common: Common,
+        #[serde(index = 2)]
+        pub status: u8,
+    }
+
+    #[test]
+    fn interleaved() {
+        let response = Response {
+            data: 42,
+            common: Common {
+                version: 2,
+                flags: Some(7),
+            },
+            status: 0,
+        };
+        assert_tokens(
+            &response,
+            &[
+                Token::Map { len: Some(4) },
+                Token::I64(1),
+                Token::U8(2),
+                Token::I64(2),
+                Token::U8(0),
+                Token::I64(3),
+                Token::Some,
+                Token::U8(7),
+                Token::I64(4),
+                Token::U8(42),
+                Token::MapEnd,
+            ],
+        );
+
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&response, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..size],
+            [0xa4, 0x01, 0x02, 0x02, 0x00, 0x03, 0x07, 0x04, 0x18, 0x2a]
+        );
+        let deserialized: Response = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, response);
+    }
+
+    #[test]
+    fn skipped_inner_field() {
+        assert_tokens(
+            &Response {
+                data: 42,
+                common: Common {
+                    version: 2,
+                    flags: None,
+                },
+                status: 0,
+            },
+            &[
+                Token::Map { len: Some(3) },
+                Token::I64(1),
+                Token::U8(2),
+                Token::I64(2),
+                Token::U8(0),
+                Token::I64(4),
+                Token::U8(42),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn missing_inner_field() {
+        serde_test::assert_de_tokens_error::<Response>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(2),
+                Token::U8(0),
+                Token::I64(4),
+                Token::U8(42),
+                Token::MapEnd,
+            ],
+            "missing field `version`",
+        );
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed)]
+    pub struct Outer {
+        #[serde(index = 0)]
+        pub data: u8,
+        #[serde(flatten)]
+        pub response: Response,
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(deny_unknown_fields)]
+    pub struct Strict {
+        #[serde(index = 2)]
+        pub status: u8,
+        #[serde(flatten)]
+        pub common: Common,
+    }
+
+    #[test]
+    fn nested() {
+        // The entries of all levels are serialized in canonical order
+        serde_test::assert_ser_tokens(
+            &Outer {
+                data: 5,
+                response: Response {
+                    data: 42,
+                    common: Common {
+                        version: 2,
+                        flags: Some(7),
+                    },
+                    status: 0,
+                },
+            },
+            &[
+                Token::Map { len: Some(5) },
+                Token::I64(0),
+                Token::U8(5),
+                Token::I64(1),
+                Token::U8(2),
+                Token::I64(2),
+                Token::U8(0),
+                Token::I64(3),
+                Token::Some,
+                Token::U8(7),
+                Token::I64(4),
+                Token::U8(42),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub struct Curve {
+        #[serde(index = -1)]
+        pub crv: u8,
+        #[serde(index = 100)]
+        pub large: u8,
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub struct Mixed {
+        #[serde(index = -2)]
+        pub x: u8,
+        #[serde(flatten)]
+        pub curve: Curve,
+        #[serde(index = 1)]
+        pub kty: u8,
+    }
+
+    #[test]
+    fn unsigned_before_negative() {
+        let mixed = Mixed {
+            x: 4,
+            curve: Curve { crv: 3, large: 2 },
+            kty: 1,
+        };
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&mixed, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..size],
+            [0xa4, 0x01, 0x01, 0x18, 0x64, 0x02, 0x20, 0x03, 0x21, 0x04]
+        );
+        let deserialized: Mixed = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, mixed);
+    }
+
+    #[test]
+    fn deny_unknown_fields() {
+        serde_test::assert_de_tokens_error::<Strict>(
+            &[Token::Map { len: Some(1) }, Token::I64(9)],
+            "unknown index `9`, expected `2` or an index of the flattened field `common`",
+        );
+    }
+}
+
+mod tagged {
+    use super::*;
+    use serde_test::assert_de_tokens_error;
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub struct OkpKey {
+        #[serde(index = -1)]
+        pub crv: i32,
+        #[serde(index = -2)]
+        pub x: u8,
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub struct SymmetricKey {
+        #[serde(index = 3, skip_serializing_if = "Option::is_none")]
+        pub alg: Option<i32>,
+        #[serde(index = -1)]
+        pub k: u8,
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(tag = 1)]
+    pub enum CoseKey {
+        #[serde(tag_value = 1)]
+        Okp(OkpKey),
+        #[serde(tag_value = 4)]
+        Symmetric(SymmetricKey),
+        #[serde(tag_value = 0)]
+        Reserved,
+    }
+
+    #[test]
+    fn newtype_variant() {
+        assert_tokens(
+            &CoseKey::Okp(OkpKey { crv: 6, x: 42 }),
+            &[
+                Token::Map { len: Some(3) },
+                Token::I64(1),
+                Token::I64(1),
+                Token::I64(-1),
+                Token::I32(6),
+                Token::I64(-2),
+                Token::U8(42),
+                Token::MapEnd,
+            ],
+        );
+
+        let key = CoseKey::Symmetric(SymmetricKey { alg: Some(5), k: 7 });
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&key, &mut buf).unwrap();
+        // The tag is placed in canonical order before index 3
+        assert_eq!(&buf[..size], [0xa3, 0x01, 0x04, 0x03, 0x05, 0x20, 0x07]);
+        let deserialized: CoseKey = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, key);
+    }
+
+    #[test]
+    fn unit_variant() {
+        assert_tokens(
+            &CoseKey::Reserved,
+            &[
+                Token::Map { len: Some(1) },
+                Token::I64(1),
+                Token::I64(0),
+                Token::MapEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_de_tokens_error::<CoseKey>(
+            &[
+                Token::Map { len: Some(1) },
+                Token::I64(1),
+                Token::I64(2),
+                Token::MapEnd,
+            ],
+            "invalid value: integer `2`, expected a tag value of the enum",
+        );
+        assert_de_tokens_error::<CoseKey>(
+            &[Token::Map { len: Some(2) }, Token::I64(-1), Token::I32(6)],
+            "expected the tag `1` as first key, found `-1`",
+        );
+        assert_de_tokens_error::<CoseKey>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(1),
+                Token::I64(1),
+                Token::I64(-2),
+                Token::U8(42),
+                Token::MapEnd,
+            ],
+            "missing field `crv`",
+        );
+        assert_de_tokens_error::<StrictKey>(
+            &[
+                Token::Map { len: Some(3) },
+                Token::I64(1),
+                Token::I64(4),
+                Token::I64(-1),
+                Token::U8(7),
+                Token::I64(-9),
+            ],
+            "unknown index `-9`, expected `-1`",
+        );
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(deny_unknown_fields)]
+    pub struct StrictSymmetricKey {
+        #[serde(index = -1)]
+        pub k: u8,
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(tag = 1)]
+    pub enum StrictKey {
+        #[serde(tag_value = 4)]
+        Symmetric(StrictSymmetricKey),
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub struct Payload {
+        #[serde(index = 1)]
+        pub value: u8,
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(tag = 3, tag_first)]
+    pub enum Message {
+        #[serde(tag_value = 1)]
+        Data(Payload),
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed)]
+    #[serde_indexed(tag = 3)]
+    pub enum CanonicalMessage {
+        #[serde(tag_value = 1)]
+        Data(Payload),
+    }
+
+    #[test]
+    fn canonical_tag() {
+        // Without tag_first, the tag is serialized in canonical order
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&CanonicalMessage::Data(Payload { value: 7 }), &mut buf).unwrap();
+        assert_eq!(&buf[..size], [0xa2, 0x01, 0x07, 0x03, 0x01]);
+    }
+
+    #[test]
+    fn tag_first() {
+        // With tag_first, the tag is serialized before the inner key that sorts before it
+        let message = Message::Data(Payload { value: 7 });
+        assert_tokens(
+            &message,
+            &[
+                Token::Map { len: Some(2) },
+                Token::I64(3),
+                Token::I64(1),
+                Token::I64(1),
+                Token::U8(7),
+                Token::MapEnd,
+            ],
+        );
+
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&message, &mut buf).unwrap();
+        assert_eq!(&buf[..size], [0xa2, 0x03, 0x01, 0x01, 0x07]);
+        let deserialized: Message = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, message);
+    }
+}
+
+mod externally_tagged {
+    use super::*;
+    use serde_test::assert_de_tokens_error;
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub struct SetPin {
+        #[serde(index = 1)]
+        pub protocol: u8,
+        #[serde(index = 2)]
+        pub pin: u32,
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub enum Command {
+        #[serde(index = 1)]
+        Reset,
+        #[serde(index = 2)]
+        SetPin(SetPin),
+        #[serde(index = 3)]
+        Wink(u8),
+    }
+
+    #[test]
+    fn newtype_variant() {
+        assert_tokens(
+            &Command::SetPin(SetPin {
+                protocol: 2,
+                pin: 1234,
+            }),
+            &[
+                Token::Map { len: Some(1) },
+                Token::U64(2),
+                Token::Map { len: Some(2) },
+                Token::U64(1),
+                Token::U8(2),
+                Token::U64(2),
+                Token::U32(1234),
+                Token::MapEnd,
+                Token::MapEnd,
+            ],
+        );
+
+        let command = Command::Wink(3);
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&command, &mut buf).unwrap();
+        assert_eq!(&buf[..size], [0xa1, 0x03, 0x03]);
+        let deserialized: Command = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, command);
+    }
+
+    #[test]
+    fn unit_variant() {
+        assert_tokens(&Command::Reset, &[Token::U64(1)]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_de_tokens_error::<Command>(
+            &[Token::Map { len: Some(1) }, Token::U64(4)],
+            "invalid value: integer `4`, expected an index or a map with a single entry of Command",
+        );
+        assert_de_tokens_error::<Command>(
+            &[
+                Token::Map { len: Some(2) },
+                Token::U64(3),
+                Token::U8(3),
+                Token::U64(3),
+            ],
+            "invalid length 2, expected an index or a map with a single entry of Command",
+        );
+        assert_de_tokens_error::<Command>(
+            &[Token::U64(2)],
+            "invalid value: integer `2`, expected an index or a map with a single entry of Command",
+        );
+    }
+}
+
+mod other {
+    use super::*;
+    use serde_test::assert_de_tokens_error;
+
+    #[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub enum Algorithm {
+        #[serde(index = -7)]
+        Es256,
+        #[serde(index = -8)]
+        EdDsa,
+        #[serde(other)]
+        Unknown(i64),
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub enum Extension {
+        #[serde(index = 1)]
+        CredProtect,
+        #[serde(index = 2)]
+        HmacSecret,
+        #[serde(other)]
+        Unknown(i64),
+    }
+
+    #[test]
+    fn known() {
+        assert_tokens(&Algorithm::Es256, &[Token::I64(-7)]);
+        assert_tokens(&Extension::HmacSecret, &[Token::I64(2)]);
+        // Unsigned values are still accepted
+        serde_test::assert_de_tokens(&Extension::HmacSecret, &[Token::U64(2)]);
+    }
+
+    #[test]
+    fn unknown() {
+        assert_tokens(&Algorithm::Unknown(-257), &[Token::I64(-257)]);
+        assert_tokens(&Algorithm::Unknown(3), &[Token::I64(3)]);
+        assert_tokens(&Extension::Unknown(7), &[Token::I64(7)]);
+        assert_tokens(&Extension::Unknown(-1), &[Token::I64(-1)]);
+
+        let mut buf = [0u8; 16];
+        let size = cbor_serialize(&Algorithm::Unknown(-37), &mut buf).unwrap();
+        assert_eq!(&buf[..size], [0x38, 0x24]);
+        let deserialized: Algorithm = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, Algorithm::Unknown(-37));
+    }
+
+    #[test]
+    fn negative_unknown() {
+        // Extension has no negative indices, but the value of the other variant can be negative
+        let mut buf = [0u8; 16];
+        let size = cbor_serialize(&Extension::Unknown(-5), &mut buf).unwrap();
+        assert_eq!(&buf[..size], [0x24]);
+        let deserialized: Extension = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, Extension::Unknown(-5));
+
+        let size = cbor_serialize(&Extension::HmacSecret, &mut buf).unwrap();
+        assert_eq!(&buf[..size], [0x02]);
+        let deserialized: Extension = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, Extension::HmacSecret);
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_de_tokens_error::<Extension>(
+            &[Token::U64(u64::MAX)],
+            "invalid value: integer `18446744073709551615`, expected an index of Extension",
+        );
+    }
+}
+
+mod label {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error};
+
+    #[derive(Clone, Copy, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub enum Algorithm {
+        #[serde(index = -7, label = "ES256")]
+        Es256,
+        #[serde(index = -8, label = "EdDSA")]
+        EdDsa,
+        #[serde(index = -257)]
+        Rs256,
+    }
+
+    #[test]
+    fn integer_or_text() {
+        assert_tokens(&Algorithm::Es256, &[Token::I64(-7)]);
+        assert_de_tokens(&Algorithm::Es256, &[Token::Str("ES256")]);
+        assert_de_tokens(&Algorithm::EdDsa, &[Token::BorrowedStr("EdDSA")]);
+        assert_de_tokens(&Algorithm::Rs256, &[Token::I64(-257)]);
+
+        let mut buf = [0u8; 16];
+        let size = cbor_serialize(&"EdDSA", &mut buf).unwrap();
+        let deserialized: Algorithm = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, Algorithm::EdDsa);
+    }
+
+    #[test]
+    fn unknown_label() {
+        assert_de_tokens_error::<Algorithm>(
+            &[Token::Str("RS256")],
+            "invalid value: string \"RS256\", expected an index or a label of Algorithm",
+        );
+    }
+}
+
+mod text_index {
+    use super::*;
+    use serde_test::{assert_de_tokens, assert_de_tokens_error};
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    pub struct Options {
+        #[serde(index = "up", skip_serializing_if = "Option::is_none")]
+        pub up: Option<bool>,
+        #[serde(index = "rk")]
+        pub rk: bool,
+        #[serde(index = "plat", default)]
+        pub plat: bool,
+    }
+
+    #[derive(Clone, Debug, PartialEq, SerializeIndexed, DeserializeIndexed)]
+    #[serde_indexed(deny_unknown_fields)]
+    pub struct Header {
+        #[serde(index = "private")]
+        pub private: u8,
+        #[serde(index = -1, alias = 4)]
+        pub kid: u8,
+        #[serde(index = 1, validate = "check_alg")]
+        pub alg: i8,
+    }
+
+    fn check_alg(alg: &i8) -> Result<(), &'static str> {
+        if *alg < 0 {
+            Ok(())
+        } else {
+            Err("unsupported algorithm")
+        }
+    }
+
+    #[test]
+    fn text_keys() {
+        let options = Options {
+            up: Some(false),
+            rk: true,
+            plat: false,
+        };
+        assert_tokens(
+            &options,
+            &[
+                Token::Map { len: Some(3) },
+                Token::Str("rk"),
+                Token::Bool(true),
+                Token::Str("up"),
+                Token::Some,
+                Token::Bool(false),
+                Token::Str("plat"),
+                Token::Bool(false),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens(
+            &Options {
+                up: None,
+                rk: true,
+                plat: false,
+            },
+            &[
+                Token::Map { len: Some(2) },
+                Token::Str("uv"),
+                Token::Bool(true),
+                Token::Str("rk"),
+                Token::Bool(true),
+                Token::MapEnd,
+            ],
+        );
+
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&options, &mut buf).unwrap();
+        let deserialized: Options = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, options);
+    }
+
+    #[test]
+    fn mixed_keys() {
+        let header = Header {
+            private: 7,
+            kid: 3,
+            alg: -7,
+        };
+        assert_tokens(
+            &header,
+            &[
+                Token::Map { len: Some(3) },
+                Token::I64(1),
+                Token::I8(-7),
+                Token::I64(-1),
+                Token::U8(3),
+                Token::Str("private"),
+                Token::U8(7),
+                Token::MapEnd,
+            ],
+        );
+        assert_de_tokens(
+            &header,
+            &[
+                Token::Map { len: Some(3) },
+                Token::Str("private"),
+                Token::U8(7),
+                Token::U64(4),
+                Token::U8(3),
+                Token::I64(1),
+                Token::I8(-7),
+                Token::MapEnd,
+            ],
+        );
+
+        let mut buf = [0u8; 64];
+        let size = cbor_serialize(&header, &mut buf).unwrap();
+        let deserialized: Header = cbor_deserialize(&mut buf[..size]).unwrap();
+        assert_eq!(deserialized, header);
+    }
+
+    #[test]
+    fn errors() {
+        assert_de_tokens_error::<Header>(
+            &[Token::Map { len: Some(1) }, Token::Str("public")],
+            "unknown index `\"public\"`, expected one of `-1`, `1`, `\"private\"`",
+        );
+        assert_de_tokens_error::<Header>(
+            &[Token::Map { len: Some(1) }, Token::I64(2)],
+            "unknown index `2`, expected one of `-1`, `1`, `\"private\"`",
+        );
+        assert_de_tokens_error::<Header>(
+            &[Token::Map { len: Some(1) }, Token::I64(1), Token::I8(2)],
+            "invalid value for field `alg` at index `1`: unsupported algorithm",
+        );
+        assert_de_tokens_error::<Options>(
+            &[Token::Map { len: Some(0) }, Token::MapEnd],
+            "missing field `rk`",
+        );
+    }
+}